@@ -35,7 +35,11 @@
 
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 use core::cell::{Cell, UnsafeCell};
+use core::ops::{Index, IndexMut};
 
 /// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
 /// an array, return a reference to an array of cells.
@@ -57,8 +61,464 @@ use core::cell::{Cell, UnsafeCell};
 /// assert_eq!(cell.into_inner(), [99, 2, 3]);
 /// ```
 pub fn array_of_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> &[Cell<T>; N] {
+    CellExt::as_array_of_cells(cell)
+}
+
+/// An extension trait providing method-style versions of the free functions in this crate.
+///
+/// Recent versions of the standard library include an inherent
+/// [`Cell::as_array_of_cells`](https://doc.rust-lang.org/std/cell/struct.Cell.html#method.as_array_of_cells)
+/// method, which takes precedence over this trait in method call syntax. The two do exactly the
+/// same thing.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::CellExt;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 3]> = Cell::new([1, 2, 3]);
+/// cell.as_array_of_cells()[0].set(99);
+/// assert_eq!(cell.into_inner(), [99, 2, 3]);
+/// ```
+pub trait CellExt<T, const N: usize> {
+    /// See [`array_of_cells`].
+    fn as_array_of_cells(&self) -> &[Cell<T>; N];
+}
+
+impl<T, const N: usize> CellExt<T, N> for Cell<[T; N]> {
+    fn as_array_of_cells(&self) -> &[Cell<T>; N] {
+        // SAFETY: `Cell<T>` has the same memory layout as `T`.
+        unsafe { &*(self as *const Cell<[T; N]> as *const [Cell<T>; N]) }
+    }
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a two-dimensional array, return a reference to a two-dimensional array of cells.
+///
+/// Like [`array_of_cells`], this is a single pointer cast, with no runtime cost.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::array_of_cells_2d;
+/// # use core::cell::Cell;
+/// let cell: Cell<[[i32; 2]; 2]> = Cell::new([[1, 2], [3, 4]]);
+/// let grid: &[[Cell<i32>; 2]; 2] = array_of_cells_2d(&cell);
+/// grid[1][0].set(99);
+/// assert_eq!(cell.into_inner(), [[1, 2], [99, 4]]);
+/// ```
+pub fn array_of_cells_2d<T, const N: usize, const M: usize>(
+    cell: &Cell<[[T; M]; N]>,
+) -> &[[Cell<T>; M]; N] {
+    // SAFETY: `Cell<T>` has the same memory layout as `T`.
+    unsafe { &*(cell as *const Cell<[[T; M]; N]> as *const [[Cell<T>; M]; N]) }
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a two-dimensional array, return a reference to a flat array of cells in row-major order.
+///
+/// Stable Rust doesn't allow `N * M` in the return type, so the length of the flat array has to be
+/// a separate parameter `L`. It's usually inferred from the type that the result is assigned to,
+/// like in the example below, and otherwise it can be given as `flatten_cells::<_, N, M, L>`. It's
+/// a compile-time error if `L` isn't equal to `N * M`. Internally this is a pointer cast, with no
+/// runtime cost.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::flatten_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[[i32; 3]; 2]> = Cell::new([[1, 2, 3], [4, 5, 6]]);
+/// let flat: &[Cell<i32>; 6] = flatten_cells(&cell);
+/// flat[3].set(99);
+/// assert_eq!(cell.into_inner(), [[1, 2, 3], [99, 5, 6]]);
+/// ```
+pub fn flatten_cells<T, const N: usize, const M: usize, const L: usize>(
+    cell: &Cell<[[T; M]; N]>,
+) -> &[Cell<T>; L] {
+    const { assert!(L == N * M, "the flattened length must be N * M") };
+    // SAFETY: `Cell<T>` has the same memory layout as `T`, and nested arrays are laid out
+    // contiguously with no padding between rows. We checked the length above.
+    unsafe { &*(cell as *const Cell<[[T; M]; N]> as *const [Cell<T>; L]) }
+}
+
+/// Given a reference to an array of cells, return a reference to a
+/// [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing the whole array.
+///
+/// This is the inverse of [`array_of_cells`]. The returned reference has the same lifetime as the
+/// input, and internally this is a pointer cast, with no runtime cost.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::cell_of_array;
+/// # use core::cell::Cell;
+/// let array: [Cell<i32>; 3] = [Cell::new(1), Cell::new(2), Cell::new(3)];
+/// let cell: &Cell<[i32; 3]> = cell_of_array(&array);
+/// cell.set([4, 5, 6]);
+/// assert_eq!(array[0].get(), 4);
+/// ```
+pub fn cell_of_array<T, const N: usize>(cells: &[Cell<T>; N]) -> &Cell<[T; N]> {
     // SAFETY: `Cell<T>` has the same memory layout as `T`.
-    unsafe { &*(cell as *const Cell<[T; N]> as *const [Cell<T>; N]) }
+    unsafe { &*(cells as *const [Cell<T>; N] as *const Cell<[T; N]>) }
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a slice, return a slice of cells.
+///
+/// This is the unsized counterpart of [`array_of_cells`]. The length of the returned slice is the
+/// same as the length of the original, and empty slices are fine. Internally this is a pointer
+/// cast, with no runtime cost.
+///
+/// This is equivalent to the standard
+/// [`Cell::as_slice_of_cells`](https://doc.rust-lang.org/std/cell/struct.Cell.html#method.as_slice_of_cells)
+/// method.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::slice_of_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 5]> = Cell::new([1, 2, 3, 4, 5]);
+/// let slice_cell: &Cell<[i32]> = &cell;
+/// let slice: &[Cell<i32>] = slice_of_cells(slice_cell);
+/// slice[1].set(99);
+/// assert_eq!(cell.into_inner(), [1, 99, 3, 4, 5]);
+/// ```
+pub fn slice_of_cells<T>(cell: &Cell<[T]>) -> &[Cell<T>] {
+    // SAFETY: `Cell<T>` has the same memory layout as `T`, and the cast preserves the length.
+    unsafe { &*(cell as *const Cell<[T]> as *const [Cell<T>]) }
+}
+
+/// Given a slice of cells, return a reference to a
+/// [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing the whole slice.
+///
+/// This is the inverse of [`slice_of_cells`], and the unsized counterpart of [`cell_of_array`].
+/// The length is preserved, and internally this is a pointer cast, with no runtime cost.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::{cell_slice_from_cells, slice_of_cells};
+/// # use core::cell::Cell;
+/// fn zero(cell: &Cell<[i32]>) {
+///     for element in slice_of_cells(cell) {
+///         element.set(0);
+///     }
+/// }
+/// let cells: [Cell<i32>; 4] = [Cell::new(1), Cell::new(2), Cell::new(3), Cell::new(4)];
+/// zero(cell_slice_from_cells(&cells[1..3]));
+/// assert_eq!(cells.map(Cell::into_inner), [1, 0, 0, 4]);
+/// ```
+pub fn cell_slice_from_cells<T>(cells: &[Cell<T>]) -> &Cell<[T]> {
+    // SAFETY: `Cell<T>` has the same memory layout as `T`, and the cast preserves the length.
+    unsafe { &*(cells as *const [Cell<T>] as *const Cell<[T]>) }
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array, return two slices of cells, split at `mid`.
+///
+/// The first slice contains the elements `[0, mid)` and the second contains `[mid, N)`. The two
+/// slices don't overlap. This is [`array_of_cells`] followed by
+/// [`split_at`](https://doc.rust-lang.org/std/primitive.slice.html#method.split_at).
+///
+/// # Panics
+///
+/// Panics if `mid > N`.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::split_array_of_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 4]> = Cell::new([1, 2, 3, 4]);
+/// let (left, right) = split_array_of_cells(&cell, 1);
+/// left[0].set(10);
+/// right[0].set(20);
+/// assert_eq!(cell.into_inner(), [10, 20, 3, 4]);
+/// ```
+pub fn split_array_of_cells<T, const N: usize>(
+    cell: &Cell<[T; N]>,
+    mid: usize,
+) -> (&[Cell<T>], &[Cell<T>]) {
+    array_of_cells(cell).split_at(mid)
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array, return an iterator over chunks of cells, `chunk_size` elements at a time.
+///
+/// Each chunk is a slice of cells, and the chunks don't overlap. If `chunk_size` doesn't divide
+/// `N`, the last chunk is shorter. This is [`array_of_cells`] followed by
+/// [`chunks`](https://doc.rust-lang.org/std/primitive.slice.html#method.chunks).
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is zero.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::chunks_of_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 5]> = Cell::new([1, 2, 3, 4, 5]);
+/// for chunk in chunks_of_cells(&cell, 2) {
+///     chunk[0].set(0);
+/// }
+/// assert_eq!(cell.into_inner(), [0, 2, 0, 4, 0]);
+/// ```
+pub fn chunks_of_cells<T, const N: usize>(
+    cell: &Cell<[T; N]>,
+    chunk_size: usize,
+) -> impl Iterator<Item = &[Cell<T>]> {
+    array_of_cells(cell).chunks(chunk_size)
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array, return an iterator over cells of its elements.
+///
+/// This is shorthand for `array_of_cells(cell).iter()`.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::iter_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 3]> = Cell::new([1, 2, 3]);
+/// for c in iter_cells(&cell) {
+///     c.set(c.get() + 1);
+/// }
+/// assert_eq!(cell.into_inner(), [2, 3, 4]);
+/// ```
+pub fn iter_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> core::slice::Iter<'_, Cell<T>> {
+    array_of_cells(cell).iter()
+}
+
+/// Given references to two [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html)s
+/// containing arrays of the same length, return an iterator over pairs of cells of their elements.
+///
+/// This is shorthand for `iter_cells(a).zip(iter_cells(b))`. Since these are cells, it's fine if
+/// `a` and `b` are the same array.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::zip_cells;
+/// # use core::cell::Cell;
+/// let a: Cell<[i32; 3]> = Cell::new([1, 2, 3]);
+/// let b: Cell<[i32; 3]> = Cell::new([0; 3]);
+/// for (x, y) in zip_cells(&a, &b) {
+///     y.set(x.get() * 10);
+/// }
+/// assert_eq!(b.into_inner(), [10, 20, 30]);
+/// ```
+pub fn zip_cells<'a, A, B, const N: usize>(
+    a: &'a Cell<[A; N]>,
+    b: &'a Cell<[B; N]>,
+) -> impl Iterator<Item = (&'a Cell<A>, &'a Cell<B>)> {
+    iter_cells(a).zip(iter_cells(b))
+}
+
+/// Given a mutable reference to an array, return a reference to an array of cells.
+///
+/// This is shorthand for [`Cell::from_mut`](https://doc.rust-lang.org/std/cell/struct.Cell.html#method.from_mut)
+/// followed by [`array_of_cells`].
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::array_of_cells_from_mut;
+/// # use core::cell::Cell;
+/// let mut array = [1, 2, 3];
+/// let cells: &[Cell<i32>; 3] = array_of_cells_from_mut(&mut array);
+/// cells[0].set(cells[2].get());
+/// assert_eq!(array, [3, 2, 3]);
+/// ```
+pub fn array_of_cells_from_mut<T, const N: usize>(array: &mut [T; N]) -> &[Cell<T>; N] {
+    array_of_cells(Cell::from_mut(array))
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array of [`MaybeUninit`](core::mem::MaybeUninit) slots, return a reference to an array of
+/// cells of slots.
+///
+/// This is [`array_of_cells`] with the element type spelled out. Each slot can be written through
+/// its cell while the others are still uninitialized, which is fine because `MaybeUninit` doesn't
+/// require its contents to be initialized. Assuming that the slots are initialized afterwards is
+/// up to the caller.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::array_of_cells_uninit;
+/// # use core::cell::Cell;
+/// # use core::mem::MaybeUninit;
+/// let cell: Cell<[MaybeUninit<i32>; 3]> = Cell::new([MaybeUninit::uninit(); 3]);
+/// for (i, slot) in array_of_cells_uninit(&cell).iter().enumerate() {
+///     slot.set(MaybeUninit::new(i as i32));
+/// }
+/// // SAFETY: Every slot was initialized above.
+/// let array = cell.into_inner().map(|slot| unsafe { slot.assume_init() });
+/// assert_eq!(array, [0, 1, 2]);
+/// ```
+pub fn array_of_cells_uninit<T, const N: usize>(
+    cell: &Cell<[core::mem::MaybeUninit<T>; N]>,
+) -> &[Cell<core::mem::MaybeUninit<T>>; N] {
+    array_of_cells(cell)
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array, return a reference to the cell of one element, or `None` if the index is out of
+/// bounds.
+///
+/// This is the cell equivalent of
+/// [`slice::get`](https://doc.rust-lang.org/std/primitive.slice.html#method.get).
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::get_cell;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 3]> = Cell::new([1, 2, 3]);
+/// get_cell(&cell, 2).unwrap().set(99);
+/// assert!(get_cell(&cell, 3).is_none());
+/// assert_eq!(cell.into_inner(), [1, 2, 99]);
+/// ```
+pub fn get_cell<T, const N: usize>(cell: &Cell<[T; N]>, index: usize) -> Option<&Cell<T>> {
+    array_of_cells(cell).get(index)
+}
+
+/// Given a reference to an [`Rc`](alloc::rc::Rc) of a
+/// [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing an array, return a
+/// reference to an array of cells.
+///
+/// This is [`array_of_cells`] applied to the contents of the `Rc`, and the returned reference
+/// borrows from the `Rc`. It requires the `alloc` feature, which is on by default.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::rc_array_of_cells;
+/// # use core::cell::Cell;
+/// # use std::rc::Rc;
+/// let rc = Rc::new(Cell::new([1, 2, 3]));
+/// let rc2 = rc.clone();
+/// rc_array_of_cells(&rc)[0].set(99);
+/// assert_eq!(rc2.get(), [99, 2, 3]);
+/// ```
+#[cfg(feature = "alloc")]
+pub fn rc_array_of_cells<T, const N: usize>(rc: &alloc::rc::Rc<Cell<[T; N]>>) -> &[Cell<T>; N] {
+    array_of_cells(rc)
+}
+
+/// Reinterpret a slice of cells of one plain-old-data type as a slice of cells of another.
+///
+/// This is the cell equivalent of
+/// [`bytemuck::try_cast_slice`](https://docs.rs/bytemuck/1/bytemuck/fn.try_cast_slice.html), and
+/// it fails in the same cases, if the slice isn't aligned for `B` or its size in bytes isn't a
+/// multiple of `B`'s size. The returned cells alias the original ones. It requires the `bytemuck`
+/// feature.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::{slice_of_cells, try_cast_cells};
+/// # use core::cell::Cell;
+/// let cell: &Cell<[u32]> = &Cell::new([0u32; 2]);
+/// let words = slice_of_cells(cell);
+/// let bytes: &[Cell<u8>] = try_cast_cells(words).unwrap();
+/// assert_eq!(bytes.len(), 8);
+/// bytes[4].set(0xff);
+/// assert_eq!(words[1].get(), u32::from_ne_bytes([0xff, 0, 0, 0]));
+/// ```
+#[cfg(feature = "bytemuck")]
+pub fn try_cast_cells<A: bytemuck::Pod, B: bytemuck::Pod>(
+    cells: &[Cell<A>],
+) -> Result<&[Cell<B>], bytemuck::PodCastError> {
+    // SAFETY: Cell<A> has the same layout as A, and nothing writes to the cells while this
+    // temporary slice exists. It's only used to check the cast, and the new slice is derived from
+    // the original pointer, so that it's allowed to write.
+    let values: &[A] = unsafe { core::slice::from_raw_parts(cells.as_ptr().cast(), cells.len()) };
+    let len = bytemuck::try_cast_slice::<A, B>(values)?.len();
+    // SAFETY: bytemuck checked the size and alignment, and any bit pattern is a valid B.
+    Ok(unsafe { core::slice::from_raw_parts(cells.as_ptr().cast(), len) })
+}
+
+/// Like [`try_cast_cells`], but panic if the cast fails, like
+/// [`bytemuck::cast_slice`](https://docs.rs/bytemuck/1/bytemuck/fn.cast_slice.html).
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::{array_of_cells, cast_cells};
+/// # use core::cell::Cell;
+/// let cell: Cell<[u16; 2]> = Cell::new([1, 2]);
+/// let bytes: &[Cell<u8>] = cast_cells(array_of_cells(&cell));
+/// bytes[0].set(0);
+/// bytes[1].set(0);
+/// assert_eq!(cell.into_inner(), [0, 2]);
+/// ```
+#[cfg(feature = "bytemuck")]
+pub fn cast_cells<A: bytemuck::Pod, B: bytemuck::Pod>(cells: &[Cell<A>]) -> &[Cell<B>] {
+    match try_cast_cells(cells) {
+        Ok(cast) => cast,
+        Err(e) => panic!("cast_cells: {:?}", e),
+    }
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a two-dimensional array, return an array of slices of cells, one for each row.
+///
+/// This is [`array_of_cells_2d`] with each row converted to a slice, which is convenient when the
+/// rows need to be passed around or iterated without their length in the type.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::rows_of_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[[i32; 2]; 2]> = Cell::new([[1, 2], [3, 4]]);
+/// for row in rows_of_cells(&cell) {
+///     row[0].set(0);
+/// }
+/// assert_eq!(cell.into_inner(), [[0, 2], [0, 4]]);
+/// ```
+pub fn rows_of_cells<T, const N: usize, const M: usize>(
+    cell: &Cell<[[T; M]; N]>,
+) -> [&[Cell<T>]; N] {
+    let grid = array_of_cells_2d(cell);
+    core::array::from_fn(|i| &grid[i][..])
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// an array, return a reference to an array of chunks, each of which is an array of `C` cells.
+///
+/// This is the fixed-size version of [`chunks_of_cells`], and the inverse of [`flatten_cells`].
+/// Stable Rust doesn't allow `N / C` in the return type, so like the length in `flatten_cells`, the
+/// number of chunks has to be a separate parameter `K`, which is usually inferred from the type
+/// that the result is assigned to. It's a compile-time error if `C` is zero or if `K * C` isn't
+/// equal to `N`.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::array_chunks_of_cells;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 4]> = Cell::new([1, 2, 3, 4]);
+/// let chunks: &[[Cell<i32>; 2]; 2] = array_chunks_of_cells(&cell);
+/// chunks[1][0].set(99);
+/// assert_eq!(cell.into_inner(), [1, 2, 99, 4]);
+/// ```
+pub fn array_chunks_of_cells<T, const N: usize, const C: usize, const K: usize>(
+    cell: &Cell<[T; N]>,
+) -> &[[Cell<T>; C]; K] {
+    const {
+        assert!(
+            C > 0 && K * C == N,
+            "the chunks must exactly cover the array"
+        )
+    };
+    // SAFETY: This is the same layout argument as in flatten_cells, in reverse.
+    unsafe { &*(cell as *const Cell<[T; N]> as *const [[Cell<T>; C]; K]) }
 }
 
 /// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
@@ -80,6 +540,8 @@ pub fn array_of_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> &[Cell<T>; N] {
 /// assert_eq!(foo.bar.baz, 99);
 /// ```
 ///
+/// Fields with keyword names work too, using raw identifiers like `project!(cell.r#type)`.
+///
 /// `project!` will automatically take a reference if needed:
 ///
 /// ```
@@ -98,8 +560,8 @@ pub fn array_of_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> &[Cell<T>; N] {
 /// assert_eq!(foo_cell.into_inner().bar.baz, 99);
 /// ```
 ///
-/// If you want to use any expression other than a bare variable name, you need to surround it with
-/// an extra set of parentheses:
+/// If you want to use any expression other than a bare variable name, followed by a path, you need
+/// to surround it with an extra set of parentheses:
 ///
 /// ```
 /// # use cell_utils::project;
@@ -116,6 +578,17 @@ pub fn array_of_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> &[Cell<T>; N] {
 /// assert_eq!(foo.bar.baz, 99);
 /// ```
 ///
+/// With no path at all, `project!` returns the cell itself, and any expression works without
+/// parentheses. This is mostly useful in other macros that build up paths:
+///
+/// ```
+/// # use cell_utils::project;
+/// # use core::cell::Cell;
+/// let mut x = 42;
+/// project!(Cell::from_mut(&mut x)).set(99);
+/// assert_eq!(x, 99);
+/// ```
+///
 /// `project!` also supports tuples:
 ///
 /// ```
@@ -125,45 +598,1151 @@ pub fn array_of_cells<T, const N: usize>(cell: &Cell<[T; N]>) -> &[Cell<T>; N] {
 /// project!((&tuple).0).set("hello");
 /// assert_eq!(tuple.into_inner().0, "hello");
 /// ```
+///
+/// And indexing into arrays, which can be mixed with field access. Indexing out of bounds panics,
+/// like normal indexing:
+///
+/// ```
+/// # use cell_utils::project;
+/// # use core::cell::Cell;
+/// struct Grid {
+///     rows: [[i32; 2]; 2],
+/// }
+/// let grid = Cell::new(Grid { rows: [[1, 2], [3, 4]] });
+/// let i = 1;
+/// project!(grid.rows[i][0]).set(99);
+/// assert_eq!(grid.into_inner().rows, [[1, 2], [99, 4]]);
+/// ```
+///
+/// The index can be any `usize` expression, including a const generic parameter. Only arrays can be
+/// indexed, not slices or `Vec`s, because their elements aren't necessarily stored in the cell.
+/// A `Vec` keeps them in a separate allocation, which setting the `Vec` through the original cell
+/// could free.
+///
+/// Unlike normal field access, the path can't continue through a `Box`, a reference, or anything
+/// else that implements `Deref`. The projected cell would point outside of the original cell, and
+/// setting the pointer field through the original cell could free the memory that the projected
+/// cell points to. The exception is `ManuallyDrop`, whose contents are stored inline, so a path
+/// like `foo.bar.x` works when `bar` is a `ManuallyDrop<Bar>`.
+///
+/// Tuple indices can be chained, like `project!(pair.0.1)`. Rust lexes `0.1` there as a single
+/// float literal, but it works the same as `pair.0 .1`, which is how rustfmt writes it. Indices
+/// above 11 have to be written with the space.
+///
+/// To project several fields at once, list them in braces. The result is a tuple of cells:
+///
+/// ```
+/// # use cell_utils::project;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     a: i32,
+///     b: &'static str,
+/// }
+/// let foo = Cell::new(Foo { a: 1, b: "one" });
+/// let (a, b) = project!(foo.{a, b});
+/// a.set(2);
+/// b.set("two");
+/// let foo = foo.into_inner();
+/// assert_eq!((foo.a, foo.b), (2, "two"));
+/// ```
+///
+/// A path can end with `: Type` to annotate the type of the projected field. This makes errors
+/// point at the projection when the type isn't what you expected, and it helps inference in
+/// generic code:
+///
+/// ```
+/// # use cell_utils::project;
+/// # use core::cell::Cell;
+/// fn reset_first<T: Default, U>(pair: &Cell<(T, U)>) {
+///     project!(pair.0: T).set(Default::default());
+/// }
+/// let pair = Cell::new((5, "five"));
+/// reset_first(&pair);
+/// assert_eq!(pair.into_inner(), (0, "five"));
+/// ```
+///
+/// There's no way to project into an enum variant. The projected cell would outlive the match, and
+/// setting the enum to a different variant through the original cell would leave it pointing at a
+/// field that no longer exists.
 #[macro_export]
 macro_rules! project {
-    ($e:ident $(. $field:tt)* ) => {
-        // Add a & automatically. If $e is already a reference, that's fine, because a double
-        // reference will get automatically dereferenced below.
-        project!((&$e) $(. $field)*)
+    // Internal rules that walk the path one segment at a time. `$p` is a raw pointer to the current
+    // place, and `$c` is the original cell, which bounds the lifetime of the result. Each field is
+    // a single addr_of_mut! on the pointer, so no references to the contents exist while the rest
+    // of the path is evaluated.
+    (@path $c:ident $p:ident) => {{
+        // This might be nested in the caller's unsafe block, for example in project_mut!.
+        #[allow(unused_unsafe)]
+        let cell = unsafe { $crate::__private::project_ptr($c, $p) };
+        cell
+    }};
+    // Several fields at once. Duplicate fields are rejected by the borrow checker, because they
+    // would be two mutable borrows of the same place.
+    (@path $c:ident $p:ident . { $($field:tt),+ $(,)? }) => {{
+        let $p = $crate::__project_field!(@unwrap $p);
+        $crate::__project_field!(@check $p);
+        $(const { $crate::__private::assert_single_index(stringify!($field)) };)+
+        #[allow(unused_unsafe)]
+        let reference = unsafe { $crate::__private_get_mut($crate::__private::project_ptr($c, $p)) };
+        ($($crate::__private::Cell::from_mut(&mut reference.$field),)+)
+    }};
+    // A trailing type annotation pins the type of the result.
+    (@path $c:ident $p:ident : $ty:ty) => {{
+        #[allow(unused_unsafe)]
+        let cell: &$crate::__private::Cell<$ty> = unsafe { $crate::__private::project_ptr($c, $p) };
+        cell
+    }};
+    (@path $c:ident $p:ident . $field:tt $($rest:tt)*) => {
+        $crate::__project_field!($c $p $field $($rest)*)
+    };
+    (@path $c:ident $p:ident [ $index:expr ] $($rest:tt)*) => {{
+        // The index expression might read the cell, so evaluate it before going any further.
+        let index: usize = $index;
+        let $p = $crate::__private::index_array($p, index);
+        $crate::project!(@path $c $p $($rest)*)
+    }};
+    // Add a & automatically. If $e is already a reference, that's fine, because a double reference
+    // will get automatically dereferenced below. These rules only match a variable name followed
+    // by the start of a path, so that other expressions can fall through to the last rule.
+    ($e:ident) => {
+        $crate::project!((&$e))
+    };
+    ($e:ident . $($rest:tt)*) => {
+        $crate::project!((&$e) . $($rest)*)
+    };
+    ($e:ident [ $($index:tt)* ] $($rest:tt)*) => {
+        $crate::project!((&$e) [ $($index)* ] $($rest)*)
+    };
+    ($e:ident : $($rest:tt)*) => {
+        $crate::project!((&$e) : $($rest)*)
     };
-    (( $e:expr ) $(. $field:tt)* ) => {{
-        // If cell is a double reference, this automatically dereferences it.
-        let cell: &core::cell::Cell<_> = $e;
-        // SAFETY: We need this helper function to bind the lifetime of the reference.
-        unsafe fn get_mut<T>(cell: &core::cell::Cell<T>) -> &mut T { &mut *cell.as_ptr() }
-        let reference = unsafe { get_mut(cell) };
-        $( let reference = &mut reference.$field; )*
-        core::cell::Cell::from_mut(reference)
+    (( $e:expr ) $($rest:tt)*) => {{
+        // If cell is a double reference, or a reference to a Box or an Rc, this automatically
+        // dereferences it. See ProjectInput.
+        let cell: &$crate::__private::Cell<_> = $crate::__private::ProjectInput::into_cell($e);
+        let ptr = cell.as_ptr();
+        $crate::project!(@path cell ptr $($rest)*)
     }};
+    // Any other expression, with no path. This returns the cell itself.
+    ($e:expr) => {
+        $crate::project!(($e))
+    };
 }
 
-// This is a hacky way of using doctests to guarantee that something fails to compile, as described
-// in https://stackoverflow.com/a/55327334/823869. Note that these tests tends to be fragile: We
-// want them to fail for a specific reason, but new errors might get introduced that make them fail
-// for different reasons, and then we'd no longer be testing what we thought we were. I'm not sure
-// there's much we can do about that other than occasionally removing these `compile_fail`
-// annotations and sanity checking that the errors look like what we expect.
-//
-// In this case, we're testing lifetime errors. This exercises the lifetime bindings commented on
-// in the macro code above.
-/// ```compile_fail
-/// use std::cell::Cell;
-/// use cell_utils::project;
-/// let y = {
-///     let x = Cell::new(5);
-///     // FAIL: This reference outlives x.
-///     project!((&x))
-/// };
-/// y.set(6)
-/// ```
+// One field step of project!.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __project_field {
+    // Fail to compile if $p points to something that implements Deref. See NoDeref.
+    (@check $p:ident) => {
+        $crate::__private::assert_no_deref($p, {
+            #[allow(unused_imports)]
+            use $crate::__private::{NotViaDeref as _, ViaDeref as _};
+            (&$crate::__private::NoDeref::new($p)).check()
+        })
+    };
+    // If $p points to a ManuallyDrop, point to its contents instead. See SkipManuallyDrop.
+    (@unwrap $p:ident) => {{
+        #[allow(unused_imports)]
+        use $crate::__private::{NotManuallyDrop as _, ViaManuallyDrop as _};
+        (&$crate::__private::SkipManuallyDrop($p)).skip()
+    }};
+    // Named fields are always a single step. Anything else is a tuple index, and might be two of
+    // them lexed as one float literal. See __split_tuple_index!.
+    ($c:ident $p:ident $field:ident $($rest:tt)*) => {
+        $crate::__project_field!(@field $c $p $field $($rest)*)
+    };
+    ($c:ident $p:ident $field:tt $($rest:tt)*) => {
+        $crate::__split_tuple_index!([$] $c $p $field $($rest)*)
+    };
+    (@field $c:ident $p:ident $field:tt $($rest:tt)*) => {{
+        let $p = $crate::__project_field!(@unwrap $p);
+        // Type-check the field access without running it. The field can't be reached through a
+        // Deref impl, because that could lead anywhere, for example into a Box that the parent
+        // cell can replace, and it can't be a union field, which is unsafe to read.
+        #[allow(unused_unsafe)]
+        let _ = || {
+            $crate::__project_field!(@check $p);
+            let reference = unsafe { &mut *$p };
+            let _ = &mut reference.$field;
+        };
+        const { $crate::__private::assert_single_index(stringify!($field)) };
+        // SAFETY: The field is part of the place that $p points to, per the check above.
+        #[allow(unused_unsafe)]
+        let $p = unsafe { $crate::__private::addr_of_mut!((*$p).$field) };
+        $crate::project!(@path $c $p $($rest)*)
+    }};
+}
+
+// Rust lexes chained tuple indices like `.0.1` as a single float literal, and macro_rules can't
+// take a literal apart. Instead, this zips up the table below into a local macro with one rule per
+// literal, which splits it into two steps so that the second index gets checked like any other
+// field. Any other literal, including a plain integer index, is a single field step. `$d` is a `$`
+// token for the local macro's own metavariables.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __split_tuple_index {
+    (@rows $d:tt $args:tt [$($done:tt)*] [$($b:tt)*] $a:tt [$($lit:tt)*] $($rows:tt)*) => {
+        $crate::__split_tuple_index!(@rows $d $args [$($done)* $(($lit $a $b))*] [$($b)*] $($rows)*)
+    };
+    (@rows [$d:tt] [$c:ident $p:ident $($path:tt)*] [$(($lit:tt $a:tt $b:tt))*] $columns:tt) => {{
+        macro_rules! split {
+            $(($lit $d($d rest:tt)*) => {
+                $crate::project!(@path $c $p . $a . $b $d($d rest)*)
+            };)*
+            ($d field:tt $d($d rest:tt)*) => {
+                $crate::__project_field!(@field $c $p $d field $d($d rest)*)
+            };
+        }
+        split!($($path)*)
+    }};
+    ($d:tt $($args:tt)*) => {
+        $crate::__split_tuple_index!(@rows $d [$($args)*] [] [0 1 2 3 4 5 6 7 8 9 10 11]
+            0 [0.0 0.1 0.2 0.3 0.4 0.5 0.6 0.7 0.8 0.9 0.10 0.11]
+            1 [1.0 1.1 1.2 1.3 1.4 1.5 1.6 1.7 1.8 1.9 1.10 1.11]
+            2 [2.0 2.1 2.2 2.3 2.4 2.5 2.6 2.7 2.8 2.9 2.10 2.11]
+            3 [3.0 3.1 3.2 3.3 3.4 3.5 3.6 3.7 3.8 3.9 3.10 3.11]
+            4 [4.0 4.1 4.2 4.3 4.4 4.5 4.6 4.7 4.8 4.9 4.10 4.11]
+            5 [5.0 5.1 5.2 5.3 5.4 5.5 5.6 5.7 5.8 5.9 5.10 5.11]
+            6 [6.0 6.1 6.2 6.3 6.4 6.5 6.6 6.7 6.8 6.9 6.10 6.11]
+            7 [7.0 7.1 7.2 7.3 7.4 7.5 7.6 7.7 7.8 7.9 7.10 7.11]
+            8 [8.0 8.1 8.2 8.3 8.4 8.5 8.6 8.7 8.8 8.9 8.10 8.11]
+            9 [9.0 9.1 9.2 9.3 9.4 9.5 9.6 9.7 9.8 9.9 9.10 9.11]
+            10 [10.0 10.1 10.2 10.3 10.4 10.5 10.6 10.7 10.8 10.9 10.10 10.11]
+            11 [11.0 11.1 11.2 11.3 11.4 11.5 11.6 11.7 11.8 11.9 11.10 11.11]
+        )
+    };
+}
+
+/// Like [`project!`], but return a mutable reference to the field instead of a cell.
+///
+/// `project_mut!` accepts the same paths as `project!`, except for the multi-field form. The
+/// returned reference has the same lifetime as the cell it was projected from.
+///
+/// # Safety
+///
+/// Unlike `project!`, this isn't safe on its own, because `&Cell<T>` is a shared reference, and
+/// nothing stops it from being projected twice. So `project_mut!` has to be called in an `unsafe`
+/// block, and the caller must make sure that nothing else reads or writes the projected place
+/// (through the original cell or any other path) while the mutable reference is alive.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_mut;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     bar: (i32, i32),
+/// }
+/// let foo = Cell::new(Foo { bar: (1, 2) });
+/// // SAFETY: Nothing else touches foo while bar is alive.
+/// let bar: &mut (i32, i32) = unsafe { project_mut!(foo.bar) };
+/// bar.0 += 10;
+/// bar.1 += 20;
+/// assert_eq!(foo.into_inner().bar, (11, 22));
+/// ```
+#[macro_export]
+macro_rules! project_mut {
+    ($($path:tt)+) => {
+        // This call is deliberately not wrapped in an unsafe block.
+        $crate::__private_get_mut($crate::project!($($path)+))
+    };
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a union, return a reference to the cell of one of its fields.
+///
+/// The syntax is `project_union!(cell.field)`, and like [`project!`], the input can also be any
+/// expression in parentheses. Only a single union field is supported, but the result can be
+/// projected further with `project!`.
+///
+/// # Safety
+///
+/// Reading a union field is unsafe, because the bytes there might not be a valid value of the
+/// field's type, and the same goes for reading the projected cell. So `project_union!` has to be
+/// called in an `unsafe` block, and the caller asserts that the field is the active one, or at
+/// least that its bytes are valid for its type, whenever the cell is read. Writing through the
+/// cell is always fine, and it makes that field the active one.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_union;
+/// # use core::cell::Cell;
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// union Bits {
+///     int: u32,
+///     float: f32,
+/// }
+/// let bits = Cell::new(Bits { int: 0 });
+/// // SAFETY: Every bit pattern is a valid u32 and a valid f32.
+/// let (int, float) = unsafe { (project_union!(bits.int), project_union!(bits.float)) };
+/// float.set(1.0);
+/// assert_eq!(int.get(), 1.0f32.to_bits());
+/// ```
+#[macro_export]
+macro_rules! project_union {
+    ($e:ident . $field:ident) => {
+        $crate::project_union!((&$e).$field)
+    };
+    (( $e:expr ) . $field:ident) => {{
+        let cell: &$crate::__private::Cell<_> = $crate::__private::ProjectInput::into_cell($e);
+        let ptr = cell.as_ptr();
+        // A union can implement Deref too, and then a missing field would be looked up there.
+        $crate::__project_field!(@check ptr);
+        // These calls are deliberately not wrapped in an unsafe block.
+        $crate::__private::project_ptr(cell, $crate::__private::addr_of_mut!((*ptr).$field))
+    }};
+}
+
+/// Like [`project!`], but for a path that leads to a raw pointer, and return a cell of the value
+/// that it points to.
+///
+/// The path can be anything that `project!` accepts, as long as it leads to a single `*mut T` or
+/// `*const T`, and the result is a `&Cell<T>` with the same lifetime as the original cell.
+///
+/// # Safety
+///
+/// Nothing checks the pointer, so `project_raw!` has to be called in an `unsafe` block. The caller
+/// must make sure that the pointer is valid for reads and writes of `T`, even if it's a `*const
+/// T`, and that nothing else accesses the pointee except through cells like this one while the
+/// returned cell is alive. Null pointers are caught in debug builds, but only as a courtesy.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_raw;
+/// # use core::cell::Cell;
+/// struct Node {
+///     value: *mut i32,
+/// }
+/// let mut value = 1;
+/// let node = Cell::new(Node { value: &mut value });
+/// // SAFETY: value outlives the projected cell, and nothing else uses it in the meantime.
+/// let cell: &Cell<i32> = unsafe { project_raw!(node.value) };
+/// cell.set(2);
+/// assert_eq!(value, 2);
+/// ```
+#[macro_export]
+macro_rules! project_raw {
+    ($($path:tt)+) => {{
+        let field = $crate::project!($($path)+);
+        // This call is deliberately not wrapped in an unsafe block.
+        $crate::__private::deref_raw(field, field.get())
+    }};
+}
+
+/// Like [`project!`], but for a path that leads to an array, and return an array of cells.
+///
+/// This is shorthand for [`array_of_cells`] applied to the result of `project!`.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_array;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     grid: [i32; 3],
+/// }
+/// let foo = Cell::new(Foo { grid: [1, 2, 3] });
+/// let grid: &[Cell<i32>; 3] = project_array!(foo.grid);
+/// grid[1].set(99);
+/// assert_eq!(foo.into_inner().grid, [1, 99, 3]);
+/// ```
+#[macro_export]
+macro_rules! project_array {
+    ($($path:tt)+) => {
+        $crate::array_of_cells($crate::project!($($path)+))
+    };
+}
+
+/// Like [`project!`], but return a copy of the field's value instead of a reference to it.
+///
+/// This is shorthand for `project!(...).get()`, so the field type must be `Copy`. Since nothing is
+/// borrowed, the result can be held across an `.await` or any other code that might modify the
+/// cell.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_copy;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     bar: (i32, i32),
+/// }
+/// let foo = Cell::new(Foo { bar: (1, 2) });
+/// let second: i32 = project_copy!(foo.bar.1);
+/// assert_eq!(second, 2);
+/// ```
+#[macro_export]
+macro_rules! project_copy {
+    ($($path:tt)+) => {
+        $crate::project!($($path)+).get()
+    };
+}
+
+/// Like [`project!`], but for a path that leads to a field that's a `Cell` itself, and return a
+/// reference to that cell, rather than a cell of a cell.
+///
+/// A `Cell<Cell<T>>` has the same layout as a `Cell<T>`, so this is just a pointer cast.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_flatten;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     counter: Cell<i32>,
+/// }
+/// let foo = Cell::new(Foo { counter: Cell::new(1) });
+/// let counter: &Cell<i32> = project_flatten!(foo.counter);
+/// counter.set(2);
+/// assert_eq!(foo.into_inner().counter.get(), 2);
+/// ```
+#[macro_export]
+macro_rules! project_flatten {
+    ($($path:tt)+) => {
+        $crate::__private_flatten_cell($crate::project!($($path)+))
+    };
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a struct, return a tuple of cells for all of its fields.
+///
+/// The caller names the struct and lists its fields, like `project_all!(cell => Foo { a, b })`,
+/// and the tuple is in the same order. This is like the multi-field form of [`project!`], except
+/// that leaving out a field is a compile error.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_all;
+/// # use core::cell::Cell;
+/// struct Foo {
+///     a: i32,
+///     b: &'static str,
+/// }
+/// let foo = Cell::new(Foo { a: 1, b: "one" });
+/// let (a, b) = project_all!(foo => Foo { a, b });
+/// a.set(2);
+/// b.set("two");
+/// let foo = foo.into_inner();
+/// assert_eq!((foo.a, foo.b), (2, "two"));
+/// ```
+#[macro_export]
+macro_rules! project_all {
+    ($e:ident => $($rest:tt)*) => {
+        $crate::project_all!((&$e) => $($rest)*)
+    };
+    (( $e:expr ) => $($name:ident)::+ { $($field:ident),* $(,)? }) => {{
+        let cell: &$crate::__private::Cell<_> = $crate::__private::ProjectInput::into_cell($e);
+        // SAFETY: The mutable reference only lasts for this statement. The pattern splits it into a
+        // mutable reference per field right away, and each of those goes back into a cell below.
+        // There's no `..` in the pattern, so it doesn't compile if any fields are missing, and it
+        // can't match a union, because reading a union field would need its own unsafe block.
+        #[allow(unused_unsafe)]
+        let $($name)::+ { $($field),* } = unsafe { $crate::__private_get_mut(cell) };
+        ($($crate::__private::Cell::from_mut($field),)*)
+    }};
+}
+
+/// Given a reference to a [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing
+/// a tuple, return a tuple of cells for all of its elements.
+///
+/// This is like [`array_of_cells`], but for tuples, whose elements can have different types.
+/// Tuples of up to 12 elements are supported.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::tuple_of_cells;
+/// # use core::cell::Cell;
+/// let tuple = Cell::new((1, "one", 1.0));
+/// let (a, b, c) = tuple_of_cells!(tuple);
+/// a.set(2);
+/// b.set("two");
+/// c.set(2.0);
+/// assert_eq!(tuple.get(), (2, "two", 2.0));
+/// ```
+#[macro_export]
+macro_rules! tuple_of_cells {
+    ($e:ident) => {
+        $crate::tuple_of_cells!((&$e))
+    };
+    (( $e:expr )) => {
+        $crate::__private::TupleOfCells::tuple_of_cells($crate::__private::ProjectInput::into_cell(
+            $e,
+        ))
+    };
+    ($e:expr) => {
+        $crate::tuple_of_cells!(($e))
+    };
+}
+
+/// Given a reference to a
+/// [`RefCell`](https://doc.rust-lang.org/std/cell/struct.RefCell.html), mutably borrow it and
+/// return a [`RefMut`](https://doc.rust-lang.org/std/cell/struct.RefMut.html) of one of its fields
+/// or elements.
+///
+/// This accepts the same field and index paths as [`project!`]. Unlike `project!`, the result is a
+/// guard that holds the `RefCell` borrowed until it's dropped, and this panics if the `RefCell` is
+/// already borrowed.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::project_ref_mut;
+/// # use core::cell::{RefCell, RefMut};
+/// struct Foo {
+///     bar: Bar,
+/// }
+/// struct Bar {
+///     baz: String,
+/// }
+/// let foo = RefCell::new(Foo { bar: Bar { baz: "hello".into() } });
+/// let mut baz: RefMut<String> = project_ref_mut!(foo.bar.baz);
+/// baz.push_str(" world");
+/// assert!(foo.try_borrow().is_err());
+/// drop(baz);
+/// assert_eq!(foo.borrow().bar.baz, "hello world");
+/// ```
+#[macro_export]
+macro_rules! project_ref_mut {
+    ($e:ident $($rest:tt)*) => {
+        $crate::project_ref_mut!((&$e) $($rest)*)
+    };
+    // With no path, this is just borrow_mut.
+    (( $e:expr )) => {
+        $crate::__private::RefCellInput::into_refcell($e).borrow_mut()
+    };
+    (( $e:expr ) $($rest:tt)*) => {{
+        let refcell: &$crate::__private::RefCell<_> =
+            $crate::__private::RefCellInput::into_refcell($e);
+        $crate::__private::RefMut::map(refcell.borrow_mut(), |reference| &mut reference $($rest)*)
+    }};
+}
+
+// The macros refer to these instead of core::..., so that they don't depend on what `core` means
+// at the call site.
+#[doc(hidden)]
+pub mod __private {
+    pub use core::cell::{Cell, RefCell, RefMut};
+    use core::marker::PhantomData;
+    pub use core::ptr::addr_of_mut;
+
+    // The input to project! and project_all! goes through this trait rather than a type
+    // annotation, so that passing something other than a cell gives a clear error. It's
+    // implemented for references to cells, and for references to references and smart pointers
+    // that lead to a cell, which is what the automatic & in project! produces.
+    #[diagnostic::on_unimplemented(
+        message = "the input to `project!` must be a `Cell` or a reference to one, not `{Self}`",
+        label = "expected a `Cell` or a reference to a `Cell`"
+    )]
+    pub trait ProjectInput<'a> {
+        type Target;
+
+        fn into_cell(self) -> &'a Cell<Self::Target>;
+    }
+
+    impl<'a, T> ProjectInput<'a> for &'a Cell<T> {
+        type Target = T;
+
+        fn into_cell(self) -> &'a Cell<T> {
+            self
+        }
+    }
+
+    impl<'a, T> ProjectInput<'a> for &'a mut Cell<T> {
+        type Target = T;
+
+        fn into_cell(self) -> &'a Cell<T> {
+            self
+        }
+    }
+
+    // A shared reference to a shared reference can be copied out, so this keeps the lifetime of
+    // the innermost reference.
+    impl<'a, 'b, C: ?Sized> ProjectInput<'a> for &&'b C
+    where
+        &'b C: ProjectInput<'a>,
+    {
+        type Target = <&'b C as ProjectInput<'a>>::Target;
+
+        fn into_cell(self) -> &'a Cell<Self::Target> {
+            (*self).into_cell()
+        }
+    }
+
+    impl<'a, C: ?Sized> ProjectInput<'a> for &'a &mut C
+    where
+        &'a C: ProjectInput<'a>,
+    {
+        type Target = <&'a C as ProjectInput<'a>>::Target;
+
+        fn into_cell(self) -> &'a Cell<Self::Target> {
+            (&**self).into_cell()
+        }
+    }
+
+    // The same thing for the input to project_ref_mut!.
+    #[diagnostic::on_unimplemented(
+        message = "the input to `project_ref_mut!` must be a `RefCell` or a reference to one, not `{Self}`",
+        label = "expected a `RefCell` or a reference to a `RefCell`"
+    )]
+    pub trait RefCellInput<'a> {
+        type Target;
+
+        fn into_refcell(self) -> &'a RefCell<Self::Target>;
+    }
+
+    impl<'a, T> RefCellInput<'a> for &'a RefCell<T> {
+        type Target = T;
+
+        fn into_refcell(self) -> &'a RefCell<T> {
+            self
+        }
+    }
+
+    impl<'a, T> RefCellInput<'a> for &'a mut RefCell<T> {
+        type Target = T;
+
+        fn into_refcell(self) -> &'a RefCell<T> {
+            self
+        }
+    }
+
+    impl<'a, 'b, C: ?Sized> RefCellInput<'a> for &&'b C
+    where
+        &'b C: RefCellInput<'a>,
+    {
+        type Target = <&'b C as RefCellInput<'a>>::Target;
+
+        fn into_refcell(self) -> &'a RefCell<Self::Target> {
+            (*self).into_refcell()
+        }
+    }
+
+    impl<'a, C: ?Sized> RefCellInput<'a> for &'a &mut C
+    where
+        &'a C: RefCellInput<'a>,
+    {
+        type Target = <&'a C as RefCellInput<'a>>::Target;
+
+        fn into_refcell(self) -> &'a RefCell<Self::Target> {
+            (&**self).into_refcell()
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    macro_rules! smart_pointer_inputs {
+        ($($pointer:ident)::+) => {
+            impl<'a, C: ?Sized> ProjectInput<'a> for &'a $($pointer)::+<C>
+            where
+                &'a C: ProjectInput<'a>,
+            {
+                type Target = <&'a C as ProjectInput<'a>>::Target;
+
+                fn into_cell(self) -> &'a Cell<Self::Target> {
+                    (&**self).into_cell()
+                }
+            }
+
+            impl<'a, C: ?Sized> RefCellInput<'a> for &'a $($pointer)::+<C>
+            where
+                &'a C: RefCellInput<'a>,
+            {
+                type Target = <&'a C as RefCellInput<'a>>::Target;
+
+                fn into_refcell(self) -> &'a RefCell<Self::Target> {
+                    (&**self).into_refcell()
+                }
+            }
+        };
+    }
+
+    #[cfg(feature = "alloc")]
+    smart_pointer_inputs!(alloc::boxed::Box);
+    #[cfg(feature = "alloc")]
+    smart_pointer_inputs!(alloc::rc::Rc);
+    #[cfg(feature = "alloc")]
+    smart_pointer_inputs!(alloc::sync::Arc);
+
+    // project! can't follow a field through a Deref impl, because the target could be anywhere,
+    // and nothing stops the parent cell from replacing the field and freeing it. This picks
+    // ViaDeref::check if the pointee implements Deref, and NotViaDeref::check otherwise, which is
+    // autoref specialization. The Deref version returns a type that assert_no_deref rejects.
+    pub struct NoDeref<T: ?Sized>(PhantomData<*mut T>);
+
+    impl<T: ?Sized> NoDeref<T> {
+        pub fn new(_ptr: *mut T) -> Self {
+            NoDeref(PhantomData)
+        }
+    }
+
+    pub struct ThroughDeref;
+
+    pub trait ViaDeref {
+        fn check(&self) -> ThroughDeref {
+            ThroughDeref
+        }
+    }
+
+    impl<T: ?Sized + core::ops::Deref> ViaDeref for NoDeref<T> {}
+
+    pub trait NotViaDeref {
+        fn check(&self) {}
+    }
+
+    impl<T: ?Sized> NotViaDeref for &NoDeref<T> {}
+
+    #[diagnostic::on_unimplemented(
+        message = "`project!` can't go through `{T}`, because it implements `Deref`",
+        label = "this part of the path implements `Deref`",
+        note = "the path can't continue through a `Box`, a reference, or any other pointer"
+    )]
+    pub trait NotThroughDeref<T: ?Sized> {}
+
+    impl<T: ?Sized> NotThroughDeref<T> for () {}
+
+    pub fn assert_no_deref<T: ?Sized, C: NotThroughDeref<T>>(_ptr: *mut T, _check: C) {}
+
+    // ManuallyDrop is the one Deref impl that project! goes through, because it's
+    // repr(transparent), so its contents are part of the cell. This uses the same autoref
+    // specialization as NoDeref to cast a pointer to a ManuallyDrop into a pointer to its
+    // contents, and to leave any other pointer alone.
+    pub struct SkipManuallyDrop<T: ?Sized>(pub *mut T);
+
+    pub trait ViaManuallyDrop<T> {
+        fn skip(&self) -> *mut T;
+    }
+
+    impl<T> ViaManuallyDrop<T> for SkipManuallyDrop<core::mem::ManuallyDrop<T>> {
+        fn skip(&self) -> *mut T {
+            self.0.cast()
+        }
+    }
+
+    pub trait NotManuallyDrop<T: ?Sized> {
+        fn skip(&self) -> *mut T;
+    }
+
+    impl<T: ?Sized> NotManuallyDrop<T> for &SkipManuallyDrop<T> {
+        fn skip(&self) -> *mut T {
+            self.0
+        }
+    }
+
+    // Chained tuple indices are split up by __split_tuple_index!, but only up to a point. Anything
+    // bigger is still a float literal here, so reject it instead of skipping the checks for the
+    // second index.
+    pub const fn assert_single_index(field: &str) {
+        let bytes = field.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'.' {
+                panic!("tuple indices this large have to be chained with a space, like `.12 .0`");
+            }
+            i += 1;
+        }
+    }
+
+    // project! only indexes into arrays, whose elements are part of the cell. Other Index impls,
+    // like Vec's, could lead anywhere.
+    #[diagnostic::on_unimplemented(
+        message = "`project!` can only index into arrays, not `{Self}`",
+        label = "this isn't an array"
+    )]
+    pub trait ProjectIndex {
+        type Element;
+        const LEN: usize;
+    }
+
+    impl<T, const N: usize> ProjectIndex for [T; N] {
+        type Element = T;
+        const LEN: usize = N;
+    }
+
+    pub fn index_array<A: ProjectIndex>(ptr: *mut A, index: usize) -> *mut A::Element {
+        assert!(
+            index < A::LEN,
+            "index out of bounds: the len is {} but the index is {}",
+            A::LEN,
+            index
+        );
+        ptr.cast::<A::Element>().wrapping_add(index)
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point into the value in `cell`, and there must not be any other references to
+    /// that part of the value.
+    pub unsafe fn project_ptr<T, U>(_cell: &Cell<T>, ptr: *mut U) -> &Cell<U> {
+        &*(ptr as *const Cell<U>)
+    }
+
+    // The two kinds of raw pointer that project_raw! accepts.
+    pub trait RawPointer: Copy {
+        type Pointee;
+
+        fn into_mut(self) -> *mut Self::Pointee;
+    }
+
+    impl<T> RawPointer for *mut T {
+        type Pointee = T;
+
+        fn into_mut(self) -> *mut T {
+            self
+        }
+    }
+
+    impl<T> RawPointer for *const T {
+        type Pointee = T;
+
+        fn into_mut(self) -> *mut T {
+            self as *mut T
+        }
+    }
+
+    /// # Safety
+    ///
+    /// See project_raw!. The lifetime of the result comes from `_field`.
+    pub unsafe fn deref_raw<P: RawPointer>(_field: &Cell<P>, ptr: P) -> &Cell<P::Pointee> {
+        let ptr = ptr.into_mut();
+        debug_assert!(!ptr.is_null(), "project_raw! pointer is null");
+        &*(ptr as *const Cell<P::Pointee>)
+    }
+
+    // The implementation of tuple_of_cells!. The element pointers are computed from the tuple
+    // pointer without creating any references to the tuple, and the elements don't overlap, so the
+    // returned cells don't alias each other.
+    pub trait TupleOfCells {
+        type Cells<'a>
+        where
+            Self: 'a;
+
+        fn tuple_of_cells(cell: &Cell<Self>) -> Self::Cells<'_>;
+    }
+
+    macro_rules! tuple_impls {
+        ($($name:ident $index:tt),+) => {
+            impl<$($name),+> TupleOfCells for ($($name,)+) {
+                type Cells<'a> = ($(&'a Cell<$name>,)+) where Self: 'a;
+
+                fn tuple_of_cells(cell: &Cell<Self>) -> Self::Cells<'_> {
+                    let ptr = cell.as_ptr();
+                    // SAFETY: Each element pointer is in bounds of the tuple, and Cell<T> has the
+                    // same layout as T. The cells borrow from `cell`, so the tuple stays alive
+                    // and isn't accessed any other way while they exist.
+                    unsafe {
+                        ($(&*(core::ptr::addr_of_mut!((*ptr).$index) as *const Cell<$name>),)+)
+                    }
+                }
+            }
+        };
+    }
+
+    tuple_impls!(A 0);
+    tuple_impls!(A 0, B 1);
+    tuple_impls!(A 0, B 1, C 2);
+    tuple_impls!(A 0, B 1, C 2, D 3);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+    tuple_impls!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+}
+
+#[doc(hidden)]
+pub fn __private_flatten_cell<T>(cell: &Cell<Cell<T>>) -> &Cell<T> {
+    // SAFETY: Cell is repr(transparent), so Cell<Cell<T>> has the same layout as Cell<T>, and
+    // either one allows mutation through a shared reference.
+    unsafe { &*(cell as *const Cell<Cell<T>> as *const Cell<T>) }
+}
+
+#[doc(hidden)]
+#[allow(clippy::mut_from_ref)]
+pub unsafe fn __private_get_mut<T>(cell: &Cell<T>) -> &mut T {
+    &mut *cell.as_ptr()
+}
+
+// This is a hacky way of using doctests to guarantee that something fails to compile, as described
+// in https://stackoverflow.com/a/55327334/823869. Note that these tests tends to be fragile: We
+// want them to fail for a specific reason, but new errors might get introduced that make them fail
+// for different reasons, and then we'd no longer be testing what we thought we were. I'm not sure
+// there's much we can do about that other than occasionally removing these `compile_fail`
+// annotations and sanity checking that the errors look like what we expect.
+//
+// In this case, we're testing lifetime errors. This exercises the lifetime bindings commented on
+// in the macro code above.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// let y = {
+///     let x = Cell::new(5);
+///     // FAIL: This reference outlives x.
+///     project!((&x))
+/// };
+/// y.set(6)
+/// ```
 fn _compile_fail_test() {}
 
+// The same lifetime test for `project_mut!`.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project_mut;
+/// let y = {
+///     let x = Cell::new((5, 6));
+///     // FAIL: This reference outlives x.
+///     unsafe { project_mut!(x.0) }
+/// };
+/// *y = 7;
+/// ```
+fn _compile_fail_project_mut_lifetime() {}
+
+// `project_mut!` can't be called outside of an unsafe block.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project_mut;
+/// let x = Cell::new((5, 6));
+/// // FAIL: This requires unsafe.
+/// *project_mut!(x.0) = 7;
+/// ```
+fn _compile_fail_project_mut_unsafe() {}
+
+// The flattened length of `flatten_cells` must match the dimensions of the input.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::flatten_cells;
+/// let cell = Cell::new([[0; 3]; 2]);
+/// // FAIL: 2 * 3 is not 5.
+/// let flat: &[Cell<i32>; 5] = flatten_cells(&cell);
+/// ```
+fn _compile_fail_flatten_cells_length() {}
+
+// The chunks of `array_chunks_of_cells` must exactly cover the input.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::array_chunks_of_cells;
+/// let cell = Cell::new([0; 5]);
+/// // FAIL: 5 isn't a multiple of 2.
+/// let chunks: &[[Cell<i32>; 2]; 2] = array_chunks_of_cells(&cell);
+/// ```
+fn _compile_fail_array_chunks_of_cells_length() {}
+
+// Projecting the same field twice in one multi-field projection would produce aliasing cells.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// let x = Cell::new((1, 2));
+/// // FAIL: .0 appears twice.
+/// let (a, b) = project!(x.{0, 0});
+/// ```
+fn _compile_fail_project_duplicate_fields() {}
+
+// A type annotation that doesn't match the field is an error.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// let x = Cell::new((1i32, 2i32));
+/// // FAIL: .0 is an i32, not a u8.
+/// project!(x.0: u8);
+/// ```
+fn _compile_fail_project_wrong_type() {}
+
+// Projecting through a shared reference field is an error.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// struct Outer<'a> {
+///     inner: &'a (i32, i32),
+/// }
+/// let outer = Cell::new(Outer { inner: &(1, 2) });
+/// // FAIL: inner is a shared reference.
+/// project!(outer.inner.0);
+/// ```
+fn _compile_fail_project_through_shared_ref() {}
+
+// Projecting through a Box field is an error.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// struct Outer {
+///     inner: Box<(i32, i32)>,
+/// }
+/// let outer = Cell::new(Outer { inner: Box::new((1, 2)) });
+/// // FAIL: inner is a Box.
+/// let first = project!(outer.inner.0);
+/// ```
+fn _compile_fail_project_through_box() {}
+
+// Projecting through a &mut reference field is an error too.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// struct Outer<'a> {
+///     inner: &'a mut (i32, i32),
+/// }
+/// let mut inner = (1, 2);
+/// let outer = Cell::new(Outer { inner: &mut inner });
+/// // FAIL: inner is a reference.
+/// let first = project!(outer.inner.0);
+/// ```
+fn _compile_fail_project_through_mut_ref() {}
+
+// The same goes for a chained tuple index, which is lexed as one token.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// let outer = Cell::new((Box::new((1, 2)),));
+/// // FAIL: outer.0 is a Box.
+/// let second = project!(outer.0.1);
+/// ```
+fn _compile_fail_project_through_box_tuple_index() {}
+
+// Going into a ManuallyDrop doesn't let the path go through a Box inside it.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use std::mem::ManuallyDrop;
+/// use cell_utils::project;
+/// let outer = Cell::new((ManuallyDrop::new(Box::new((1, 2))),));
+/// // FAIL: outer.0 is a Box inside a ManuallyDrop.
+/// let second = project!(outer.0 .1);
+/// ```
+fn _compile_fail_project_through_manually_drop_box() {}
+
+// Chained indices past the end of the table in __split_tuple_index! need a space.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// let wide = Cell::new(((), (), (), (), (), (), (), (), (), (), (), (), ((1, 2),)));
+/// // FAIL: 12.0 is a single float literal.
+/// let inner = project!(wide.12.0);
+/// ```
+fn _compile_fail_project_large_chained_tuple_index() {}
+
+// project_ref_mut! needs a RefCell.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project_ref_mut;
+/// let cell = Cell::new((1, 2));
+/// // FAIL: cell is a Cell, not a RefCell.
+/// let first = project_ref_mut!(cell.0);
+/// ```
+fn _compile_fail_project_ref_mut_not_refcell() {}
+
+// Union fields need project_union!.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// #[derive(Clone, Copy)]
+/// union Bits {
+///     int: u32,
+///     float: f32,
+/// }
+/// let bits = Cell::new(Bits { int: 0 });
+/// // FAIL: Reading a union field is unsafe.
+/// let float = project!(bits.float);
+/// ```
+fn _compile_fail_project_union_field() {}
+
+// Indexing into a Vec is an error.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// struct Foo {
+///     items: Vec<i32>,
+/// }
+/// let foo = Cell::new(Foo { items: vec![1, 2] });
+/// // FAIL: items isn't an array.
+/// let first = project!(foo.items[0]);
+/// ```
+fn _compile_fail_project_vec_index() {}
+
+// Fields of packed structs might not be aligned, so they can't be projected.
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use cell_utils::project;
+/// #[repr(packed)]
+/// struct Packed {
+///     a: u8,
+///     b: u32,
+/// }
+/// let packed = Cell::new(Packed { a: 0, b: 1 });
+/// // FAIL: b is unaligned.
+/// let b = project!(packed.b);
+/// ```
+fn _compile_fail_project_packed_field() {}
+
+// project_copy! only works for Copy fields.
+/// ```compile_fail
+/// use core::cell::Cell;
+/// use cell_utils::project_copy;
+/// struct Foo {
+///     name: String,
+/// }
+/// let foo = Cell::new(Foo { name: String::new() });
+/// // FAIL: String isn't Copy.
+/// let name = project_copy!(foo.name);
+/// ```
+fn _compile_fail_project_copy_non_copy() {}
+
+// project_all! needs every field.
+/// ```compile_fail
+/// use core::cell::Cell;
+/// use cell_utils::project_all;
+/// struct Foo {
+///     a: i32,
+///     b: i32,
+/// }
+/// let foo = Cell::new(Foo { a: 1, b: 2 });
+/// // FAIL: b is missing.
+/// let (a,) = project_all!(foo => Foo { a });
+/// ```
+fn _compile_fail_project_all_missing_field() {}
+
+// The input to project! has to be a cell. (The error should say "the input to `project!` must be
+// a `Cell` or a reference to one, not `&Foo`".)
+/// ```compile_fail,E0277
+/// use cell_utils::project;
+/// struct Foo {
+///     a: i32,
+/// }
+/// let foo = Foo { a: 1 };
+/// // FAIL: foo isn't in a cell.
+/// project!(foo.a);
+/// ```
+fn _compile_fail_project_non_cell() {}
+
+// project_union! has to be called in an unsafe block.
+/// ```compile_fail,E0133
+/// use cell_utils::project_union;
+/// use std::cell::Cell;
+/// #[derive(Clone, Copy)]
+/// union Bits {
+///     int: u32,
+///     float: f32,
+/// }
+/// let bits = Cell::new(Bits { int: 0 });
+/// // FAIL: This isn't in an unsafe block.
+/// project_union!(bits.float).set(1.0);
+/// ```
+fn _compile_fail_project_union_unsafe() {}
+
+// project_raw! has to be called in an unsafe block too.
+/// ```compile_fail,E0133
+/// use cell_utils::project_raw;
+/// use std::cell::Cell;
+/// let mut x = 1;
+/// let cell = Cell::new(&mut x as *mut i32);
+/// // FAIL: This isn't in an unsafe block.
+/// project_raw!(cell).set(2);
+/// ```
+fn _compile_fail_project_raw_unsafe() {}
+
 #[repr(transparent)]
 pub struct ReadOnlyCell<T>(UnsafeCell<T>);
 
@@ -172,20 +1751,206 @@ impl<T> ReadOnlyCell<T> {
         Self(UnsafeCell::new(val))
     }
 
-    pub fn from_ref(t: &T) -> &Self {
-        // SAFETY: &ReadOnlyCell<T> is strictly less capable than &T
-        unsafe { &*(t as *const T as *const Self) }
+    pub fn from_ref(t: &T) -> &Self {
+        // SAFETY: &ReadOnlyCell<T> is strictly less capable than &T
+        unsafe { &*(t as *const T as *const Self) }
+    }
+
+    pub fn from_cell_ref(t: &Cell<T>) -> &Self {
+        // SAFETY: &ReadOnlyCell<T> is strictly less capable than &Cell<T>
+        unsafe { &*(t as *const Cell<T> as *const Self) }
+    }
+}
+
+impl<T: Copy> ReadOnlyCell<T> {
+    pub fn get(&self) -> T {
+        unsafe { *self.0.get() }
+    }
+}
+
+/// An owned [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html) containing an array,
+/// which can be indexed directly to get cells of its elements.
+///
+/// `array[i]` is equivalent to `array_of_cells(&cell)[i]`, and it panics if `i` is out of bounds.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::CellArray;
+/// let array = CellArray::new([1, 2, 3]);
+/// array[0].set(99);
+/// assert_eq!(array.into_inner(), [99, 2, 3]);
+/// ```
+#[repr(transparent)]
+pub struct CellArray<T, const N: usize>(Cell<[T; N]>);
+
+impl<T, const N: usize> CellArray<T, N> {
+    pub fn new(array: [T; N]) -> Self {
+        Self(Cell::new(array))
+    }
+
+    pub fn into_inner(self) -> [T; N] {
+        self.0.into_inner()
+    }
+
+    pub fn as_array_of_cells(&self) -> &[Cell<T>; N] {
+        array_of_cells(&self.0)
+    }
+}
+
+impl<T, const N: usize> Index<usize> for CellArray<T, N> {
+    type Output = Cell<T>;
+
+    fn index(&self, index: usize) -> &Cell<T> {
+        &array_of_cells(&self.0)[index]
+    }
+}
+
+impl<T, const N: usize> IndexMut<usize> for CellArray<T, N> {
+    fn index_mut(&mut self, index: usize) -> &mut Cell<T> {
+        let element: &mut T = &mut self.0.get_mut()[index];
+        // SAFETY: `Cell<T>` has the same memory layout as `T`, and we have exclusive access.
+        unsafe { &mut *(element as *mut T as *mut Cell<T>) }
+    }
+}
+
+/// Create a [`CellArray`] from a list of elements, like `vec!` does for a `Vec`.
+///
+/// The `CellArray` owns its elements, so it can be bound to a local and then indexed to get a
+/// `&Cell<T>` for each element, or converted to a whole array of cells with
+/// [`as_array_of_cells`](CellArray::as_array_of_cells).
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::cells;
+/// # use core::cell::Cell;
+/// let cells = cells![1, 2, 3];
+/// cells[0].set(cells[1].get() + cells[2].get());
+/// let array: &[Cell<i32>; 3] = cells.as_array_of_cells();
+/// assert_eq!(array[0].get(), 5);
+///
+/// let zeros = cells![0u8; 4];
+/// assert_eq!(zeros.into_inner(), [0; 4]);
+/// ```
+#[macro_export]
+macro_rules! cells {
+    ($elem:expr; $n:expr) => {
+        $crate::CellArray::new([$elem; $n])
+    };
+    ($($x:expr),* $(,)?) => {
+        $crate::CellArray::new([$($x),*])
+    };
+}
+
+/// A borrowed slice of [`Cell`](https://doc.rust-lang.org/std/cell/struct.Cell.html)s, with the
+/// common slice operations in one place.
+///
+/// This is a thin wrapper around `&[Cell<T>]`, and it's `Copy` like a reference. The pieces
+/// returned by [`get`](CellSlice::get), [`chunks`](CellSlice::chunks), and
+/// [`split_at`](CellSlice::split_at) borrow from the original cells, not from the `CellSlice`.
+///
+/// # Example
+///
+/// ```
+/// # use cell_utils::CellSlice;
+/// # use core::cell::Cell;
+/// let cell: Cell<[i32; 4]> = Cell::new([1, 2, 3, 4]);
+/// let slice = CellSlice::from_cell(&cell);
+/// let (front, back) = slice.split_at(2);
+/// front.copy_from_slice(&[10, 20]);
+/// back.get(1).unwrap().set(40);
+/// assert_eq!(cell.into_inner(), [10, 20, 3, 40]);
+/// ```
+pub struct CellSlice<'a, T> {
+    cells: &'a [Cell<T>],
+}
+
+impl<'a, T> CellSlice<'a, T> {
+    pub fn new(cells: &'a [Cell<T>]) -> Self {
+        Self { cells }
+    }
+
+    /// See [`slice_of_cells`].
+    pub fn from_cell(cell: &'a Cell<[T]>) -> Self {
+        Self::new(slice_of_cells(cell))
+    }
+
+    pub fn as_slice(&self) -> &'a [Cell<T>] {
+        self.cells
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'a Cell<T>> {
+        self.cells.get(index)
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'a, Cell<T>> {
+        self.cells.iter()
+    }
+
+    /// Like [`slice::chunks`](https://doc.rust-lang.org/std/primitive.slice.html#method.chunks),
+    /// the last chunk is shorter if `chunk_size` doesn't divide the length, and this panics if
+    /// `chunk_size` is zero.
+    pub fn chunks(&self, chunk_size: usize) -> impl Iterator<Item = CellSlice<'a, T>> {
+        self.cells.chunks(chunk_size).map(CellSlice::new)
+    }
+
+    /// Panics if `mid > len`.
+    pub fn split_at(&self, mid: usize) -> (CellSlice<'a, T>, CellSlice<'a, T>) {
+        let (left, right) = self.cells.split_at(mid);
+        (CellSlice::new(left), CellSlice::new(right))
+    }
+}
+
+impl<T: Copy> CellSlice<'_, T> {
+    /// Set each cell to the corresponding element of `src`.
+    ///
+    /// Like
+    /// [`slice::copy_from_slice`](https://doc.rust-lang.org/std/primitive.slice.html#method.copy_from_slice),
+    /// this panics if the lengths are different.
+    pub fn copy_from_slice(&self, src: &[T]) {
+        assert_eq!(
+            self.len(),
+            src.len(),
+            "source slice length ({}) does not match destination slice length ({})",
+            src.len(),
+            self.len(),
+        );
+        for (cell, &value) in self.cells.iter().zip(src) {
+            cell.set(value);
+        }
+    }
+}
+
+// These are implemented by hand, because deriving them would require T: Copy.
+impl<T> Clone for CellSlice<'_, T> {
+    fn clone(&self) -> Self {
+        *self
     }
+}
 
-    pub fn from_cell_ref(t: &Cell<T>) -> &Self {
-        // SAFETY: &ReadOnlyCell<T> is strictly less capable than &Cell<T>
-        unsafe { &*(t as *const Cell<T> as *const Self) }
+impl<T> Copy for CellSlice<'_, T> {}
+
+impl<'a, T> From<&'a [Cell<T>]> for CellSlice<'a, T> {
+    fn from(cells: &'a [Cell<T>]) -> Self {
+        Self::new(cells)
     }
 }
 
-impl<T: Copy> ReadOnlyCell<T> {
-    pub fn get(&self) -> T {
-        unsafe { *self.0.get() }
+impl<'a, T> IntoIterator for CellSlice<'a, T> {
+    type Item = &'a Cell<T>;
+    type IntoIter = core::slice::Iter<'a, Cell<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.cells.iter()
     }
 }
 
@@ -193,6 +1958,10 @@ impl<T: Copy> ReadOnlyCell<T> {
 mod tests {
     use super::*;
 
+    #[cfg(not(feature = "alloc"))]
+    extern crate alloc;
+    use alloc::boxed::Box;
+
     #[test]
     fn test_project() {
         struct Foo {
@@ -242,6 +2011,85 @@ mod tests {
         assert_eq!(cell.into_inner(), [99, 2, 3]);
     }
 
+    #[test]
+    fn test_cell_ext() {
+        let cell = Cell::new([1, 2, 3]);
+        cell.as_array_of_cells()[0].set(99);
+        CellExt::as_array_of_cells(&cell)[2].set(100);
+        assert_eq!(cell.into_inner(), [99, 2, 100]);
+    }
+
+    #[test]
+    fn test_array_of_cells_2d() {
+        let cell = Cell::new([[0; 3]; 3]);
+        let grid: &[[Cell<i32>; 3]; 3] = array_of_cells_2d(&cell);
+        grid[1][2].set(99);
+        assert_eq!(cell.into_inner(), [[0, 0, 0], [0, 0, 99], [0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_flatten_cells() {
+        let cell = Cell::new([[1, 2, 3], [4, 5, 6]]);
+        let flat: &[Cell<i32>; 6] = flatten_cells(&cell);
+        for (i, c) in flat.iter().enumerate() {
+            assert_eq!(c.get(), i as i32 + 1);
+            c.set(c.get() * 10);
+        }
+        assert_eq!(cell.into_inner(), [[10, 20, 30], [40, 50, 60]]);
+    }
+
+    #[test]
+    fn test_cell_of_array() {
+        let cell = Cell::new([1, 2, 3]);
+        let array: &[Cell<i32>; 3] = array_of_cells(&cell);
+        let round_trip: &Cell<[i32; 3]> = cell_of_array(array);
+        assert!(core::ptr::eq(&cell, round_trip));
+        round_trip.set([4, 5, 6]);
+        assert_eq!(array[1].get(), 5);
+        assert_eq!(cell.into_inner(), [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_slice_of_cells() {
+        let cell = Cell::new([1, 2, 3, 4, 5]);
+        let slice_cell: &Cell<[i32]> = &cell;
+        let slice: &[Cell<i32>] = slice_of_cells(slice_cell);
+        assert_eq!(slice.len(), 5);
+        let middle = &slice[1..4];
+        assert_eq!(middle.len(), 3);
+        middle[0].set(20);
+        middle[2].set(40);
+        slice[4].set(50);
+        assert_eq!(cell.into_inner(), [1, 20, 3, 40, 50]);
+    }
+
+    #[test]
+    fn test_slice_of_cells_empty() {
+        let cell = Cell::new([0u8; 0]);
+        let slice_cell: &Cell<[u8]> = &cell;
+        assert!(slice_of_cells(slice_cell).is_empty());
+    }
+
+    #[test]
+    fn test_cell_slice_from_cells() {
+        let cell = Cell::new([1, 2, 3, 4, 5]);
+        let slice_cell: &Cell<[i32]> = &cell;
+        let slice = slice_of_cells(slice_cell);
+        let round_trip: &Cell<[i32]> = cell_slice_from_cells(slice);
+        assert_eq!(slice_cell.as_ptr(), round_trip.as_ptr());
+        assert_eq!(5, slice_of_cells(round_trip).len());
+        assert!(core::ptr::eq(slice_cell, round_trip));
+
+        // A subslice keeps its own pointer and length.
+        let middle = cell_slice_from_cells(&slice[1..4]);
+        let middle_cells = slice_of_cells(middle);
+        assert_eq!(3, middle_cells.len());
+        assert!(core::ptr::eq(&slice[1], &middle_cells[0]));
+        middle_cells[2].set(40);
+        assert!(slice_of_cells(cell_slice_from_cells(&slice[5..])).is_empty());
+        assert_eq!([1, 2, 3, 40, 5], cell.into_inner());
+    }
+
     #[test]
     fn test_read_only_cell() {
         let my_int = &mut 42;
@@ -258,4 +2106,825 @@ mod tests {
         cell_ref.set(44);
         assert_eq!(read_only_cell.get(), 44);
     }
+
+    #[test]
+    fn test_split_array_of_cells() {
+        let cell = Cell::new([1, 2, 3, 4]);
+        let (left, right) = split_array_of_cells(&cell, 2);
+        assert_eq!(left.len(), 2);
+        assert_eq!(right.len(), 2);
+        left[1].set(20);
+        right[0].set(30);
+        assert_eq!(cell.into_inner(), [1, 20, 30, 4]);
+    }
+
+    #[test]
+    fn test_split_array_of_cells_boundaries() {
+        let cell = Cell::new([1, 2, 3]);
+        let (left, right) = split_array_of_cells(&cell, 0);
+        assert!(left.is_empty());
+        assert_eq!(right.len(), 3);
+        let (left, right) = split_array_of_cells(&cell, 3);
+        assert_eq!(left.len(), 3);
+        assert!(right.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_split_array_of_cells_out_of_bounds() {
+        let cell = Cell::new([1, 2, 3]);
+        split_array_of_cells(&cell, 4);
+    }
+
+    #[test]
+    fn test_chunks_of_cells() {
+        let cell = Cell::new([1, 2, 3, 4, 5, 6, 7]);
+        let mut lengths = [0; 3];
+        for (i, chunk) in chunks_of_cells(&cell, 3).enumerate() {
+            lengths[i] = chunk.len();
+            let sum: i32 = chunk.iter().map(Cell::get).sum();
+            for c in chunk {
+                c.set(sum);
+            }
+        }
+        assert_eq!(lengths, [3, 3, 1]);
+        assert_eq!(cell.into_inner(), [6, 6, 6, 15, 15, 15, 7]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_chunks_of_cells_zero() {
+        let cell = Cell::new([1, 2, 3]);
+        let _ = chunks_of_cells(&cell, 0);
+    }
+
+    #[test]
+    fn test_cell_array() {
+        let mut array = CellArray::new([1, 2, 3]);
+        array[0].set(10);
+        let element: &Cell<i32> = &array[1];
+        element.set(element.get() * 10);
+        *array[2].get_mut() = 30;
+        assert_eq!(array.into_inner(), [10, 20, 30]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cell_array_out_of_bounds() {
+        let array = CellArray::new([1, 2, 3]);
+        array[3].set(4);
+    }
+
+    #[test]
+    fn test_iter_cells() {
+        let cell = Cell::new([0u8, 1, 2, 3]);
+        for c in iter_cells(&cell) {
+            c.set(c.get() + 1);
+        }
+        assert_eq!(cell.into_inner(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_project_index() {
+        let array = Cell::new([1, 2, 3]);
+        project!(array[2]).set(30);
+        let i = 0;
+        project!((&array)[i]).set(10);
+        assert_eq!(array.into_inner(), [10, 2, 30]);
+    }
+
+    #[test]
+    fn test_project_index_mixed() {
+        struct Foo {
+            bars: [Bar; 2],
+        }
+        struct Bar {
+            x: (i32, [i32; 3]),
+        }
+        let foo = Cell::new(Foo {
+            bars: [Bar { x: (0, [0; 3]) }, Bar { x: (0, [0; 3]) }],
+        });
+        let i = 1;
+        project!(foo.bars[i].x.1[2]).set(99);
+        project!(foo.bars[0].x.0).set(42);
+        let foo = foo.into_inner();
+        assert_eq!(foo.bars[0].x, (42, [0, 0, 0]));
+        assert_eq!(foo.bars[1].x, (0, [0, 0, 99]));
+    }
+
+    #[test]
+    fn test_project_index_reads_cell() {
+        struct Stack {
+            len: usize,
+            items: [i32; 4],
+        }
+        let stack = Cell::new(Stack {
+            len: 2,
+            items: [1, 2, 3, 4],
+        });
+        // The index reads the same cell that's being projected.
+        let top = project!(stack.items[project!(stack.len).get() - 1]);
+        top.set(20);
+        assert_eq!([1, 20, 3, 4], stack.into_inner().items);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_project_index_out_of_bounds() {
+        let array = Cell::new([1, 2, 3]);
+        let i = core::hint::black_box(3);
+        project!(array[i]).set(4);
+    }
+
+    #[test]
+    fn test_project_fields() {
+        struct Foo {
+            a: i32,
+            b: (u8, u8),
+            c: [char; 2],
+        }
+        let foo = Cell::new(Foo {
+            a: 1,
+            b: (2, 3),
+            c: ['a', 'b'],
+        });
+        let (a, b, c) = project!(foo.{a, b, c});
+        a.set(10);
+        b.set((20, 30));
+        c.set(['x', 'y']);
+        let (b0, b1) = project!(foo.b.{1, 0,});
+        b0.set(b1.get() + 1);
+        let foo = foo.into_inner();
+        assert_eq!(foo.a, 10);
+        assert_eq!(foo.b, (20, 21));
+        assert_eq!(foo.c, ['x', 'y']);
+    }
+
+    #[test]
+    fn test_project_mut() {
+        struct Foo {
+            bar: [(i32, i32); 2],
+        }
+        let foo = Cell::new(Foo {
+            bar: [(1, 2), (3, 4)],
+        });
+        let i = 1;
+        // SAFETY: Nothing else touches foo while these references are alive.
+        let pair: &mut (i32, i32) = unsafe { project_mut!(foo.bar[i]) };
+        pair.0 *= 10;
+        pair.1 *= 10;
+        let whole: &mut Foo = unsafe { project_mut!(foo) };
+        whole.bar[0].0 = 5;
+        assert_eq!(foo.into_inner().bar, [(5, 2), (30, 40)]);
+    }
+
+    #[test]
+    fn test_project_ref_mut() {
+        use core::cell::RefCell;
+
+        struct Foo {
+            bar: (i32, [i32; 2]),
+        }
+        let foo = RefCell::new(Foo { bar: (1, [2, 3]) });
+        {
+            let mut x = project_ref_mut!(foo.bar.1[1]);
+            *x = 30;
+            assert!(foo.try_borrow().is_err());
+            assert!(foo.try_borrow_mut().is_err());
+        }
+        // The borrow is released when the guard drops.
+        assert!(foo.try_borrow_mut().is_ok());
+        *project_ref_mut!((&foo).bar.0) = 10;
+        assert_eq!(foo.borrow().bar, (10, [2, 30]));
+
+        // With no path, the guard points to the whole value, including through a reference.
+        let reference = &foo;
+        let whole: core::cell::RefMut<Foo> = project_ref_mut!((&reference));
+        assert_eq!(whole.bar, (10, [2, 30]));
+        drop(whole);
+        project_ref_mut!(foo).bar.0 = 11;
+        assert_eq!(foo.into_inner().bar, (11, [2, 30]));
+    }
+
+    #[test]
+    fn test_project_array() {
+        struct Foo {
+            bar: Bar,
+        }
+        struct Bar {
+            grid: [[u8; 2]; 3],
+        }
+        let foo = Cell::new(Foo {
+            bar: Bar { grid: [[0; 2]; 3] },
+        });
+        let rows: &[Cell<[u8; 2]>; 3] = project_array!(foo.bar.grid);
+        rows[0].set([1, 2]);
+        let row: &[Cell<u8>; 2] = project_array!(foo.bar.grid[2]);
+        row[1].set(5);
+        assert_eq!(foo.into_inner().bar.grid, [[1, 2], [0, 0], [0, 5]]);
+    }
+
+    #[test]
+    fn test_project_type_annotation() {
+        fn swap_halves<T: Copy>(pair: &Cell<([T; 2], u8)>) {
+            let halves: &[Cell<T>; 2] = array_of_cells(project!(pair.0: [T; 2]));
+            halves[0].swap(&halves[1]);
+        }
+        let pair = Cell::new(([1, 2], 3));
+        swap_halves(&pair);
+        // The annotation also works after an index.
+        project!(pair.0[0]: i32).set(10);
+        assert_eq!(pair.into_inner(), ([10, 1], 3));
+    }
+
+    #[test]
+    fn test_zip_cells() {
+        let a = Cell::new([1, 2, 3]);
+        let b = Cell::new([0u64; 3]);
+        for (x, y) in zip_cells(&a, &b) {
+            y.set(x.get() as u64);
+            x.set(0);
+        }
+        assert_eq!(a.into_inner(), [0, 0, 0]);
+        assert_eq!(b.into_inner(), [1, 2, 3]);
+
+        // Zipping an array with itself is fine.
+        let c = Cell::new([1, 2, 3]);
+        for (x, y) in zip_cells(&c, &c) {
+            y.set(x.get() + 1);
+        }
+        assert_eq!(c.into_inner(), [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_array_of_cells_from_mut() {
+        let mut array = [1, 2, 3];
+        let cells = array_of_cells_from_mut(&mut array);
+        cells[0].swap(&cells[2]);
+        cells[1].set(20);
+        assert_eq!(array, [3, 20, 1]);
+    }
+
+    #[test]
+    fn test_project_through_manually_drop() {
+        use core::mem::ManuallyDrop;
+
+        struct Foo {
+            bar: ManuallyDrop<Bar>,
+            pair: ManuallyDrop<(i32, i32)>,
+        }
+        struct Bar {
+            x: i32,
+            y: (i32, i32),
+        }
+        let foo = Cell::new(Foo {
+            bar: ManuallyDrop::new(Bar { x: 1, y: (2, 3) }),
+            pair: ManuallyDrop::new((4, 5)),
+        });
+        project!(foo.bar.x).set(10);
+        project!(foo.bar.y.1).set(30);
+        let (a, b) = project!(foo.pair.{ 0, 1 });
+        a.set(b.get());
+        // The ManuallyDrop itself can still be projected, without going into it.
+        let _: &Cell<ManuallyDrop<Bar>> = project!(foo.bar);
+        let foo = foo.into_inner();
+        let bar = ManuallyDrop::into_inner(foo.bar);
+        assert_eq!(bar.x, 10);
+        assert_eq!(bar.y, (2, 30));
+        assert_eq!(*foo.pair, (5, 5));
+    }
+
+    #[test]
+    fn test_project_expr_no_path() {
+        fn get_cell(cell: &Cell<(i32, i32)>) -> &Cell<(i32, i32)> {
+            cell
+        }
+        let pair = Cell::new((1, 2));
+        assert!(core::ptr::eq(project!(get_cell(&pair)), &pair));
+        assert!(core::ptr::eq(project!((get_cell(&pair))), &pair));
+        assert!(core::ptr::eq(project!(&pair), &pair));
+        project!(get_cell(&pair)).set((3, 4));
+        project!((get_cell(&pair)).0).set(5);
+        assert_eq!(pair.into_inner(), (5, 4));
+    }
+
+    #[test]
+    fn test_cells() {
+        let cells = cells![1, 2, 3,];
+        for cell in cells.as_array_of_cells() {
+            cell.set(cell.get() * 10);
+        }
+        cells[1].set(99);
+        assert_eq!(cells.into_inner(), [10, 99, 30]);
+
+        let repeated = cells!["a"; 2];
+        repeated[0].set("b");
+        assert_eq!(repeated.into_inner(), ["b", "a"]);
+
+        let empty: CellArray<i32, 0> = cells![];
+        assert!(empty.as_array_of_cells().is_empty());
+    }
+
+    #[test]
+    fn test_project_copy() {
+        let cell = Cell::new(((1, 2), [3, 4]));
+        let x = project_copy!(cell.0 .1);
+        let y = project_copy!(cell.1[0]);
+        cell.set(((0, 0), [0, 0]));
+        assert_eq!((2, 3), (x, y));
+        assert_eq!((0, 0), project_copy!(cell.0));
+    }
+
+    #[test]
+    fn test_get_cell() {
+        let cell = Cell::new([1, 2, 3]);
+        assert_eq!(Some(1), get_cell(&cell, 0).map(Cell::get));
+        get_cell(&cell, 1).unwrap().set(20);
+        assert!(get_cell(&cell, 3).is_none());
+        assert!(get_cell(&cell, usize::MAX).is_none());
+        assert!(get_cell(&Cell::new([0u8; 0]), 0).is_none());
+        assert_eq!([1, 20, 3], cell.get());
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_rc_array_of_cells() {
+        use alloc::rc::Rc;
+
+        let rc = Rc::new(Cell::new([1, 2, 3]));
+        let rc2 = Rc::clone(&rc);
+        let cells = rc_array_of_cells(&rc);
+        cells[1].set(20);
+        assert_eq!([1, 20, 3], rc2.get());
+        rc_array_of_cells(&rc2)[2].set(30);
+        assert_eq!(30, cells[2].get());
+    }
+
+    #[test]
+    #[cfg(feature = "bytemuck")]
+    fn test_cast_cells() {
+        let words = Cell::new([0u32; 3]);
+        let words = array_of_cells(&words);
+        let bytes: &[Cell<u8>] = cast_cells(words);
+        assert_eq!(12, bytes.len());
+        words[1].set(u32::from_ne_bytes([1, 2, 3, 4]));
+        assert_eq!(3, bytes[6].get());
+        bytes[11].set(9);
+        assert_eq!(u32::from_ne_bytes([0, 0, 0, 9]), words[2].get());
+        let round_trip: &[Cell<u32>] = cast_cells(bytes);
+        assert!(core::ptr::eq(round_trip, &words[..]));
+    }
+
+    #[test]
+    #[cfg(feature = "bytemuck")]
+    fn test_cast_cells_errors() {
+        use bytemuck::PodCastError;
+
+        let words = Cell::new([0u32; 2]);
+        let bytes: &[Cell<u8>] = cast_cells(array_of_cells(&words));
+        assert_eq!(
+            Err(PodCastError::OutputSliceWouldHaveSlop),
+            try_cast_cells::<u8, u32>(&bytes[..3]),
+        );
+        assert_eq!(
+            Err(PodCastError::TargetAlignmentGreaterAndInputNotAligned),
+            try_cast_cells::<u8, u32>(&bytes[1..5]),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "bytemuck")]
+    #[should_panic(expected = "cast_cells: ")]
+    fn test_cast_cells_panic() {
+        let bytes = Cell::new([0u8; 3]);
+        let _: &[Cell<u16>] = cast_cells(array_of_cells(&bytes));
+    }
+
+    #[test]
+    fn test_project_raw_identifiers() {
+        struct Foo {
+            r#match: (i32, Bar),
+            r#type: i32,
+        }
+        struct Bar {
+            r#fn: [i32; 2],
+        }
+        let foo = Cell::new(Foo {
+            r#match: (1, Bar { r#fn: [2, 3] }),
+            r#type: 4,
+        });
+        project!(foo.r#match.0).set(10);
+        project!(foo.r#match.1.r#fn[1]).set(30);
+        let (r#match, r#type) = project!(foo.{ r#match, r#type });
+        project!(r#match.0).set(11);
+        r#type.set(40);
+        let foo = foo.into_inner();
+        assert_eq!(11, foo.r#match.0);
+        assert_eq!([2, 30], foo.r#match.1.r#fn);
+        assert_eq!(40, foo.r#type);
+    }
+
+    #[test]
+    fn test_project_all() {
+        mod inner {
+            pub struct Foo {
+                pub a: i32,
+                pub b: (i32, i32),
+                pub c: [i32; 2],
+            }
+        }
+        use inner::Foo;
+        let foo = Cell::new(Foo {
+            a: 1,
+            b: (2, 3),
+            c: [4, 5],
+        });
+        let (c, a, b) = project_all!((&foo) => inner::Foo { c, a, b, });
+        a.set(10);
+        project!(b.1).set(30);
+        array_of_cells(c)[0].set(40);
+        let foo = foo.into_inner();
+        assert_eq!(10, foo.a);
+        assert_eq!((2, 30), foo.b);
+        assert_eq!([40, 5], foo.c);
+    }
+
+    #[test]
+    fn test_tuple_of_cells() {
+        let two = Cell::new((1, "one"));
+        let (a, b) = tuple_of_cells!(two);
+        a.set(2);
+        b.set("two");
+        assert_eq!((2, "two"), two.get());
+
+        let three = Cell::new((1u8, 2u64, 3u16));
+        let (a, b, c) = tuple_of_cells!(&three);
+        // Writing each element doesn't clobber its neighbors, whatever the layout is.
+        a.set(u8::MAX);
+        c.set(u16::MAX);
+        b.set(u64::MAX);
+        assert_eq!((u8::MAX, u64::MAX, u16::MAX), three.get());
+
+        let four = Cell::new((1, [2, 3], (4, 5), 6));
+        let (a, b, c, d) = tuple_of_cells!((&four));
+        a.set(10);
+        array_of_cells(b)[1].set(30);
+        project!(c.0).set(40);
+        d.set(a.get() + 50);
+        assert_eq!((10, [2, 30], (40, 5), 60), four.get());
+    }
+
+    #[test]
+    fn test_rows_of_cells() {
+        let cell = Cell::new([[1, 2, 3], [4, 5, 6], [7, 8, 9]]);
+        let rows: [&[Cell<i32>]; 3] = rows_of_cells(&cell);
+        for c in rows[1] {
+            c.set(0);
+        }
+        assert_eq!([[1, 2, 3], [0, 0, 0], [7, 8, 9]], cell.get());
+        assert!(rows_of_cells(&Cell::new([[0u8; 0]; 2]))
+            .iter()
+            .all(|row| row.is_empty()));
+    }
+
+    #[test]
+    fn test_project_const_index() {
+        struct Foo {
+            arr: [(i32, i32); 3],
+        }
+        fn bump<const I: usize>(foo: &Cell<Foo>) {
+            let elem: &Cell<i32> = project!(foo.arr[I].1);
+            elem.set(elem.get() + 1);
+        }
+        const LAST: usize = 2;
+        let foo = Cell::new(Foo { arr: [(0, 0); 3] });
+        bump::<0>(&foo);
+        bump::<LAST>(&foo);
+        bump::<LAST>(&foo);
+        project!(foo.arr[LAST - 1].0).set(5);
+        assert_eq!([(0, 1), (5, 0), (0, 2)], foo.into_inner().arr);
+    }
+
+    #[test]
+    fn test_project_flatten() {
+        struct Foo {
+            count: Cell<i32>,
+            pairs: [Cell<(i32, i32)>; 2],
+        }
+        let foo = Cell::new(Foo {
+            count: Cell::new(1),
+            pairs: [Cell::new((0, 0)), Cell::new((0, 0))],
+        });
+        let count: &Cell<i32> = project_flatten!(foo.count);
+        count.set(count.get() + 1);
+        let pair: &Cell<(i32, i32)> = project_flatten!(foo.pairs[1]);
+        project!(pair.0).set(5);
+        // This is the field itself, not a copy.
+        assert!(core::ptr::eq(count, project!(foo.count).as_ptr()));
+        let foo = foo.into_inner();
+        assert_eq!(2, foo.count.get());
+        assert_eq!([(0, 0), (5, 0)], [foo.pairs[0].get(), foo.pairs[1].get()]);
+    }
+
+    #[test]
+    fn test_array_chunks_of_cells() {
+        let cell = Cell::new([0, 1, 2, 3, 4, 5]);
+        let chunks: &[[Cell<i32>; 2]; 3] = array_chunks_of_cells(&cell);
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(
+                [2 * i as i32, 2 * i as i32 + 1],
+                [chunk[0].get(), chunk[1].get()]
+            );
+        }
+        chunks[2][1].set(50);
+        chunks[0][0].set(-1);
+        assert_eq!([-1, 1, 2, 3, 4, 50], cell.get());
+    }
+
+    // The macros shouldn't depend on what `core` refers to at the call site.
+    mod core_shadowed {
+        #[allow(dead_code)]
+        mod core {}
+
+        use ::core::cell::{Cell, RefCell};
+
+        struct Pair {
+            a: i32,
+            b: i32,
+        }
+
+        #[test]
+        fn test_macros_with_core_shadowed() {
+            let cell = Cell::new(((1, 2), [3, 4], Some(5)));
+            crate::project!(cell.0 .1).set(20);
+            let (first, second) = crate::project!(cell.{ 0, 1 });
+            let _: &Cell<[i32; 2]> = crate::project!(second: [i32; 2]);
+            crate::project!(first.0).set(10);
+            crate::project!((&cell).1[0]).set(30);
+            crate::project_array!(cell.1)[1].set(40);
+            assert_eq!(10, crate::project_copy!(cell.0 .0));
+            let pair = Cell::new(Pair { a: 1, b: 2 });
+            let (a, b) = crate::project_all!(pair => Pair { a, b });
+            b.set(a.get());
+            crate::tuple_of_cells!(crate::project!(cell.0)).1.set(20);
+            unsafe { *crate::project_mut!(cell.0 .0) += 1 };
+            assert_eq!(((11, 20), [30, 40], Some(5)), cell.get());
+
+            let refcell = RefCell::new((1, 2));
+            *crate::project_ref_mut!(refcell.1) = 3;
+            assert_eq!((1, 3), refcell.into_inner());
+
+            let nested = Cell::new((Cell::new(1), 2));
+            crate::project_flatten!(nested.0).set(2);
+
+            let array = crate::cells![1, 2, 3];
+            array[0].set(0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn test_project_smart_pointer_input() {
+        use alloc::rc::Rc;
+
+        let boxed = Box::new(Cell::new((1, 2)));
+        project!(boxed.0).set(10);
+        let rc = Rc::new(Cell::new((1, 2)));
+        project!(rc.1).set(20);
+        assert_eq!((10, 2), boxed.get());
+        assert_eq!((1, 20), rc.get());
+    }
+
+    #[test]
+    fn test_project_reference_input() {
+        let mut plain = Cell::new((1, 2));
+        let mutable = &mut plain;
+        project!(mutable.0).set(30);
+        assert_eq!((30, 2), plain.get());
+
+        // The lifetime of the innermost reference is kept.
+        fn second<'a>(pair: &&&'a Cell<(i32, i32)>) -> &'a Cell<i32> {
+            project!(pair.1)
+        }
+        let pair = Cell::new((1, 2));
+        assert_eq!(2, second(&&&pair).get());
+    }
+
+    #[test]
+    fn test_project_addresses() {
+        use core::ptr::{self, addr_of_mut};
+
+        struct Inner {
+            x: u8,
+            y: (u16, u64),
+        }
+        struct Outer {
+            a: u32,
+            inner: Inner,
+            pair: (Inner, Inner),
+        }
+        let new_inner = || Inner { x: 1, y: (2, 3) };
+        let outer = Cell::new(Outer {
+            a: 0,
+            inner: new_inner(),
+            pair: (new_inner(), new_inner()),
+        });
+        // Each step is one pointer offset, which should land on the same address as taking the
+        // whole path at once.
+        let base = outer.as_ptr();
+        unsafe {
+            assert!(ptr::eq(project!(outer.a).as_ptr(), addr_of_mut!((*base).a)));
+            assert!(ptr::eq(
+                project!(outer.inner.x).as_ptr(),
+                addr_of_mut!((*base).inner.x)
+            ));
+            assert!(ptr::eq(
+                project!(outer.inner.y.1).as_ptr(),
+                addr_of_mut!((*base).inner.y.1)
+            ));
+            assert!(ptr::eq(
+                project!(outer.pair.1.y.0).as_ptr(),
+                addr_of_mut!((*base).pair.1.y.0)
+            ));
+        }
+        assert!(ptr::eq(
+            project!(outer.inner.x),
+            project!(outer.inner.x: u8)
+        ));
+        assert!(ptr::eq(project!(outer), project!(outer: Outer)));
+
+        // Repeat the projection in a loop, the way hot code would, and check the writes.
+        for i in 0..100 {
+            project!((&outer).inner.y.1).set(i);
+            project!(outer.pair.0.x).set(i as u8);
+        }
+        let outer = outer.into_inner();
+        assert_eq!(99, outer.inner.y.1);
+        assert_eq!(99, outer.pair.0.x);
+    }
+
+    #[test]
+    fn test_array_of_cells_uninit() {
+        use alloc::string::{String, ToString};
+        use core::mem::MaybeUninit;
+
+        let cell: Cell<[MaybeUninit<String>; 3]> = Cell::new([const { MaybeUninit::uninit() }; 3]);
+        let slots = array_of_cells_uninit(&cell);
+        // Fill the slots out of order, so that some are uninitialized while others are written.
+        slots[2].set(MaybeUninit::new("c".to_string()));
+        slots[0].set(MaybeUninit::new("a".to_string()));
+        slots[1].set(MaybeUninit::new("b".to_string()));
+        // SAFETY: Every slot was initialized above.
+        let array = cell.into_inner().map(|slot| unsafe { slot.assume_init() });
+        assert_eq!(["a", "b", "c"], array);
+    }
+
+    #[test]
+    fn test_cell_slice() {
+        use alloc::vec;
+        use alloc::vec::Vec;
+
+        let cell: Cell<[i32; 5]> = Cell::new([1, 2, 3, 4, 5]);
+        let slice = CellSlice::from_cell(&cell);
+        assert_eq!(5, slice.len());
+        assert!(!slice.is_empty());
+        assert_eq!(Some(3), slice.get(2).map(Cell::get));
+        assert!(slice.get(5).is_none());
+
+        for c in slice.iter() {
+            c.set(c.get() * 10);
+        }
+        let chunks: Vec<_> = slice.chunks(2).map(|c| c.len()).collect();
+        assert_eq!(vec![2, 2, 1], chunks);
+        let (left, right) = slice.split_at(3);
+        assert_eq!((3, 2), (left.len(), right.len()));
+        right.copy_from_slice(&[-4, -5]);
+        for chunk in left.chunks(2) {
+            chunk.get(0).unwrap().set(0);
+        }
+        let (empty, all) = slice.split_at(0);
+        assert!(empty.is_empty());
+        assert_eq!(all.as_slice().as_ptr(), slice.as_slice().as_ptr());
+        let sum: i32 = CellSlice::from(all.as_slice())
+            .into_iter()
+            .map(Cell::get)
+            .sum();
+        assert_eq!(20 - 4 - 5, sum);
+        assert_eq!([0, 20, 0, -4, -5], cell.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "source slice length (2) does not match destination slice length (3)"]
+    fn test_cell_slice_copy_from_slice_length() {
+        let cell = Cell::new([1, 2, 3]);
+        CellSlice::from_cell(&cell).copy_from_slice(&[1, 2]);
+    }
+
+    #[test]
+    fn test_project_union() {
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        union Value {
+            int: i64,
+            bytes: [u8; 8],
+            pair: (u32, u32),
+        }
+        #[derive(Clone, Copy)]
+        struct Tagged {
+            tag: u8,
+            value: Value,
+        }
+        let tagged = Cell::new(Tagged {
+            tag: 0,
+            value: Value { int: 0 },
+        });
+        let value = project!(tagged.value);
+        // SAFETY: All the fields are plain old data with no padding, so every one is always valid.
+        let (int, bytes, pair) = unsafe {
+            (
+                project_union!(value.int),
+                project_union!((&value).bytes),
+                project_union!((project!(tagged.value)).pair),
+            )
+        };
+        int.set(-1);
+        assert_eq!([0xff; 8], bytes.get());
+        array_of_cells(bytes)[0].set(0);
+        assert_eq!(i64::from_ne_bytes(bytes.get()), int.get());
+        project!(pair.1).set(0);
+        assert_eq!([0; 4], bytes.get()[4..]);
+        project!(tagged.tag).set(1);
+        let tagged = tagged.into_inner();
+        assert_eq!(1, tagged.tag);
+        assert_eq!([0, 0xff, 0xff, 0xff, 0, 0, 0, 0], unsafe {
+            tagged.value.bytes
+        });
+    }
+
+    #[test]
+    fn test_project_raw() {
+        struct Handle {
+            id: u32,
+            target: *mut i32,
+            pair: (u8, *const (i32, i32)),
+        }
+        let target = Box::into_raw(Box::new(1));
+        let pair = Box::into_raw(Box::new((2, 3)));
+        let handle = Cell::new(Handle {
+            id: 0,
+            target,
+            pair: (0, pair),
+        });
+        // SAFETY: Both pointers come from boxes that are only freed at the end, and nothing else
+        // touches them in the meantime.
+        let (target_cell, pair_cell) =
+            unsafe { (project_raw!(handle.target), project_raw!((&handle).pair.1)) };
+        target_cell.set(10);
+        project!(pair_cell.1).set(target_cell.get() + 20);
+        project!(handle.id).set(1);
+        assert_eq!(1, handle.into_inner().id);
+        // SAFETY: These are the same pointers, and the cells above are no longer used.
+        let (target, pair) = unsafe { (Box::from_raw(target), Box::from_raw(pair)) };
+        assert_eq!(10, *target);
+        assert_eq!((2, 30), *pair);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic = "project_raw! pointer is null"]
+    fn test_project_raw_null() {
+        let cell = Cell::new(core::ptr::null_mut::<i32>());
+        let _ = unsafe { project_raw!(cell) };
+    }
+
+    // rustfmt would insert spaces, like `x.0 .1`, which avoids the float tokens that this tests.
+    #[rustfmt::skip]
+    #[test]
+    fn test_project_chained_tuple_indices() {
+        // Rust lexes `0.1` in `x.0.1` as a single float literal token. Check that it gets split
+        // up, with and without a type annotation.
+        struct Wide((), (), (), (), (), (), (), (), (), (), (i32, (i32, i32)), u8);
+        let nested = Cell::new(((1, (2, 3)), 4));
+        project!(nested.0.0).set(10);
+        project!(nested.0.1.0).set(20);
+        project!(nested.0.1.1: i32).set(30);
+        assert_eq!(((10, (20, 30)), 4), nested.get());
+        let (a, b) = project!(nested.0.1.{ 0, 1 });
+        a.set(b.get());
+        assert!(core::ptr::eq(project!(nested.0.1), project!(nested.0.1: (i32, i32))));
+        assert_eq!(((10, (30, 30)), 4), nested.get());
+
+        let wide = Cell::new(Wide((), (), (), (), (), (), (), (), (), (), (1, (2, 3)), 0));
+        project!(wide.10.0).set(10);
+        project!(wide.10.1.1).set(30);
+        project!(wide.10.1.0: i32).set(20);
+        project!(wide.11).set(11);
+        let wide = wide.into_inner();
+        assert_eq!((10, (20, 30)), wide.10);
+        assert_eq!(11, wide.11);
+
+        let arrays = Cell::new(((1, [2, 3]), 0));
+        project!(arrays.0.1[1]).set(30);
+        assert_eq!(((1, [2, 30]), 0), arrays.get());
+    }
 }
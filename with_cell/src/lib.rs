@@ -1,6 +1,33 @@
-use std::cell::{Cell, UnsafeCell};
-use std::mem;
-use std::ptr;
+//! `WithCell` is `no_std` compatible, but it needs `alloc`. With the `std` feature (on by default),
+//! the borrow stack is thread-local. Without it, there's a single global borrow stack, guarded by
+//! the [`critical-section`](https://crates.io/crates/critical-section) crate. That dependency is
+//! behind the `critical-section` feature, which `no_std` builds need to enable, and the final
+//! binary needs to provide a critical section implementation.
+//!
+//! The borrow checks are always on, because they're what make `WithCell` safe to use. Code that
+//! can't afford the borrow stack bookkeeping in a hot loop can use the unsafe `with_unchecked` and
+//! `with_mut_unchecked` methods, which skip it, and which make reentrant access that would have
+//! panicked, like calling `set` from inside `with`, undefined behavior instead.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "critical-section")))]
+compile_error!("with_cell needs either the std feature or the critical-section feature");
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::cmp;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 struct OnDrop<F: FnMut()>(F);
 
@@ -10,196 +37,1440 @@ impl<F: FnMut()> Drop for OnDrop<F> {
     }
 }
 
+// Guards like WithRef can be dropped in any order, so this isn't strictly a stack. Entries for the
+// same borrow of the same cell are interchangeable, though, so it doesn't matter which one a guard
+// removes when it drops.
+//
+// This used to be an intrusive linked list of entries in the stack frames of `with`, which never
+// allocated. That doesn't work for guards: a guard moves after it's created, so a list node stored
+// inside it would dangle, and the global stack without std interleaves entries from different
+// threads, which don't come off in order. The Vec keeps its capacity, so it only allocates when
+// the stack gets deeper than it's been before, and guards dropped in order pop from the end.
+#[cfg(feature = "std")]
 thread_local! {
-    static BORROW_STACK: Cell<*const BorrowEntry> = Cell::new(ptr::null());
+    static BORROW_STACK: RefCell<Vec<BorrowEntry>> = const { RefCell::new(Vec::new()) };
+}
+
+// Without thread-locals, all threads share one stack. That's still correct, because a WithCell
+// isn't Sync, so entries for any given cell can only come from the one thread that's using it.
+// The exception is a leaked guard, e.g. from mem::forget. With a thread-local stack, its entry goes
+// away when the thread exits, but on the global stack it stays forever, and any cell that later
+// overlaps the same address, on any thread, panics as though it were still borrowed.
+#[cfg(not(feature = "std"))]
+static BORROW_STACK: critical_section::Mutex<RefCell<Vec<BorrowEntry>>> =
+    critical_section::Mutex::new(RefCell::new(Vec::new()));
+
+#[cfg(feature = "std")]
+fn with_borrow_stack<U>(f: impl FnOnce(&RefCell<Vec<BorrowEntry>>) -> U) -> U {
+    BORROW_STACK.with(f)
+}
+
+#[cfg(not(feature = "std"))]
+fn with_borrow_stack<U>(f: impl FnOnce(&RefCell<Vec<BorrowEntry>>) -> U) -> U {
+    critical_section::with(|cs| f(BORROW_STACK.borrow(cs)))
+}
+
+/// Panics if any `WithCell` on this thread is currently borrowed.
+///
+/// This is meant for the top of an event loop or similar, where nothing should be borrowed. Like
+/// `debug_assert!`, it only checks in builds with debug assertions enabled, and in release builds
+/// it does nothing. Guards pop their entries during unwinding, so a panic caught with
+/// `catch_unwind` doesn't leave anything behind. What this catches is leaked guards, e.g. from
+/// `mem::forget`.
+///
+/// Without the `std` feature there's only one global borrow stack, so this panics if any
+/// `WithCell` on *any* thread is borrowed, including a borrow that another thread is legitimately
+/// holding right now. Only call it there if you know no other thread is using `WithCell`.
+pub fn assert_borrow_stack_empty() {
+    if cfg!(debug_assertions) {
+        with_borrow_stack(|stack| assert!(stack.borrow().is_empty(), "borrow stack isn't empty"));
+    }
+}
+
+/// Returns the number of `WithCell` borrows currently active on this thread.
+///
+/// Without the `std` feature, this counts the borrows active on every thread, since they all share
+/// one global borrow stack.
+pub fn borrow_stack_depth() -> usize {
+    with_borrow_stack(|stack| stack.borrow().len())
+}
+
+/// Returns whether any `WithCell` on this thread is currently borrowed.
+///
+/// Without the `std` feature, this is true if a `WithCell` on any thread is borrowed, since they
+/// all share one global borrow stack.
+pub fn is_any_borrow_active() -> bool {
+    borrow_stack_depth() > 0
 }
 
+// Each entry records the range of memory a cell covers, since cells created with map can overlap
+// with their parent without having the same address. The location of the call that started the
+// borrow goes in panic messages, to help debug reentrancy. Entries don't record whether the cell
+// was Owned or a View, because that only matters for the cell being accessed. See Owned below.
 #[derive(Copy, Clone)]
 struct BorrowEntry {
-    cell_address: usize,
-    next: *const BorrowEntry,
+    start: usize,
+    end: usize,
+    mutable: bool,
+    location: &'static Location<'static>,
+}
+
+impl BorrowEntry {
+    fn overlaps(&self, other: &BorrowEntry) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn strictly_contains(&self, other: &BorrowEntry) -> bool {
+        self.start <= other.start
+            && other.end <= self.end
+            && (self.start, self.end) != (other.start, other.end)
+    }
+
+    // Entries that differ only in location are still interchangeable.
+    fn same_borrow(&self, other: &BorrowEntry) -> bool {
+        (self.start, self.end, self.mutable) == (other.start, other.end, other.mutable)
+    }
+}
+
+// Cells you create yourself are Owned, and the cells that map and as_cells return for part of a
+// value are Views. The difference matters when a borrow of one cell contains another cell's range.
+// If the inner cell is Owned, it's a WithCell somewhere inside the borrowed value, like a field,
+// and using it through the outer borrow is ordinary interior mutability. If it's a View, it's
+// plain data inside the borrowed value, and writing to it would change memory that the outer
+// borrow expects to be frozen. So a borrow conflicts with any cell that overlaps it, except for an
+// Owned cell strictly inside of it.
+pub enum Owned {}
+
+pub enum View {}
+
+pub trait CellKind: sealed::Sealed {
+    #[doc(hidden)]
+    const IS_VIEW: bool;
+}
+
+impl CellKind for Owned {
+    const IS_VIEW: bool = false;
+}
+
+impl CellKind for View {
+    const IS_VIEW: bool = true;
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Owned {}
+
+    impl Sealed for super::View {}
+}
+
+// These are out of line and #[cold], to keep them out of the inlined fast paths. #[track_caller]
+// still reports the caller of the method that failed the check.
+#[cold]
+#[track_caller]
+fn panic_borrowed(entry: BorrowEntry) -> ! {
+    panic!("address is borrowed (borrow started at {})", entry.location);
+}
+
+#[cold]
+#[track_caller]
+fn panic_mutably_borrowed(entry: BorrowEntry) -> ! {
+    panic!(
+        "address is mutably borrowed (borrow started at {})",
+        entry.location
+    );
 }
 
 #[repr(transparent)]
-pub struct WithCell<T>(UnsafeCell<T>);
+pub struct WithCell<T, K: CellKind = Owned>(UnsafeCell<T>, PhantomData<K>);
 
 impl<T> WithCell<T> {
     pub fn new(t: T) -> Self {
-        Self(UnsafeCell::new(t))
+        Self(UnsafeCell::new(t), PhantomData)
     }
 
     pub fn from_mut(t: &mut T) -> &Self {
         unsafe { &*(t as *mut T as *mut Self) }
     }
 
+    /// # Safety
+    ///
+    /// `ptr` must be non-null, aligned, and valid for reads and writes for the lifetime `'a`. For
+    /// that lifetime, the value must only be accessed through `WithCell`s. In particular, `ptr`
+    /// must not come from a shared reference, unless that reference is to a `WithCell` or some
+    /// other cell.
+    pub unsafe fn from_ptr<'a>(ptr: *mut T) -> &'a Self {
+        &*(ptr as *const Self)
+    }
+
     pub fn into_inner(self) -> T {
         self.0.into_inner()
     }
 
+    pub fn into_cell(self) -> Cell<T> {
+        Cell::new(self.into_inner())
+    }
+
+    pub fn from_cell(cell: Cell<T>) -> Self {
+        Self::new(cell.into_inner())
+    }
+
+    /// # Safety
+    ///
+    /// `Cell` doesn't know about the borrow stack, so for the lifetime of the returned reference,
+    /// the `Cell` must not be used to write to the value while any borrow of this `WithCell` (or of
+    /// a cell that overlaps it) is active. For example, calling `Cell::set` inside of `with` is
+    /// undefined behavior.
+    pub unsafe fn as_cell(&self) -> &Cell<T> {
+        &*(self as *const Self as *const Cell<T>)
+    }
+
+    /// # Safety
+    ///
+    /// Like [`as_cell`](Self::as_cell), the same value is reachable through both the `Cell` and
+    /// the returned `WithCell`, and the `Cell` must not be used to write to it while any borrow of
+    /// the `WithCell` is active.
+    pub unsafe fn from_cell_ref(cell: &Cell<T>) -> &Self {
+        &*(cell as *const Cell<T> as *const Self)
+    }
+
+    // Like Box::leak, this never frees the allocation or runs the destructor of the value. It's
+    // meant for values that live for the rest of the program anyway, like global singletons. As
+    // with get_mut, the &mut T doesn't need any runtime checks.
+    pub fn leak<'a>(self: Box<Self>) -> &'a mut T
+    where
+        T: 'a,
+    {
+        Box::leak(self).get_mut()
+    }
+}
+
+impl<T, K: CellKind> WithCell<T, K> {
+    pub fn as_ptr(&self) -> *mut T {
+        self.0.get()
+    }
+
+    // No runtime checks are needed here, because &mut self guarantees that nothing else is
+    // borrowing the cell.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+
+    // Like set, but with no runtime checks, for the same reason as get_mut.
+    pub fn set_unique(&mut self, t: T) {
+        *self.get_mut() = t;
+    }
+
+    // Shared borrows of the same cell can nest, since each one pushes its own entry, which works
+    // like a reader count. Mutating the cell panics while any of them is active.
+    #[track_caller]
     pub fn with<U>(&self, f: impl FnOnce(&T) -> U) -> U {
-        BORROW_STACK.with(|stack| {
-            let previous_head = stack.get();
-            let new_head = BorrowEntry {
-                cell_address: self as *const Self as usize,
-                next: previous_head,
-            };
-            stack.set(&new_head);
-            let _on_drop = OnDrop(|| stack.set(previous_head));
-            unsafe { f(&*self.0.get()) }
+        f(&self.borrow())
+    }
+
+    // The same as `with`, for call sites that nest shared reads of the cell on purpose, and want
+    // to say so. Only mutating the cell panics inside `f`.
+    #[track_caller]
+    pub fn with_shared<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        self.with(f)
+    }
+
+    // This is the same as `with`, but the signature makes it clear at the call site that `f` can
+    // fail, and it lets type inference see the error type for `?` inside the closure. The borrow
+    // ends when `f` returns, whether that's Ok or Err.
+    #[track_caller]
+    pub fn with_result<U, E>(&self, f: impl FnOnce(&T) -> Result<U, E>) -> Result<U, E> {
+        self.with(f)
+    }
+
+    // Like `with`, but return `default` instead of panicking if the cell is mutably borrowed.
+    // Shared borrows don't prevent reading, so they don't trigger the default.
+    #[track_caller]
+    pub fn with_or<U>(&self, default: U, f: impl FnOnce(&T) -> U) -> U {
+        if self.is_mutably_borrowed() {
+            default
+        } else {
+            self.with(f)
+        }
+    }
+
+    // `f` gets a plain &mut T, so it can borrow several fields mutably at once, like
+    // `cell.with_mut(|s| { s.a += 1; s.b.push(x); })`, and no other access to the cell is allowed
+    // until it returns.
+    #[track_caller]
+    pub fn with_mut<U>(&self, f: impl FnOnce(&mut T) -> U) -> U {
+        f(&mut self.borrow_mut())
+    }
+
+    /// # Safety
+    ///
+    /// This skips the borrow stack entirely, so the caller has to guarantee what `with` would have
+    /// checked: that the cell, and any cell that overlaps it, isn't mutably borrowed and isn't
+    /// written to in any way while `f` runs.
+    pub unsafe fn with_unchecked<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        f(&*self.0.get())
+    }
+
+    /// # Safety
+    ///
+    /// This skips the borrow stack entirely, so the caller has to guarantee what `with_mut` would
+    /// have checked: that the cell, and any cell that overlaps it, isn't borrowed and isn't accessed
+    /// in any way while `f` runs. With a `&mut WithCell<T>`, use `get_mut` or `set_unique`
+    /// instead, which skip the checks safely.
+    pub unsafe fn with_mut_unchecked<U>(&self, f: impl FnOnce(&mut T) -> U) -> U {
+        f(&mut *self.0.get())
+    }
+
+    #[track_caller]
+    pub fn borrow(&self) -> WithRef<'_, T, K> {
+        if let Err(entry) = self.try_push_borrow(false) {
+            panic_mutably_borrowed(entry);
+        }
+        WithRef { cell: self }
+    }
+
+    // Like borrow, but return None instead of panicking if the cell is mutably borrowed. Shared
+    // borrows nest, so this succeeds inside of `with`.
+    #[track_caller]
+    pub fn try_borrow(&self) -> Option<WithRef<'_, T, K>> {
+        self.try_push_borrow(false).ok()?;
+        Some(WithRef { cell: self })
+    }
+
+    #[track_caller]
+    pub fn borrow_mut(&self) -> WithRefMut<'_, T, K> {
+        if let Err(entry) = self.try_push_borrow(true) {
+            panic_borrowed(entry);
+        }
+        WithRefMut { cell: self }
+    }
+
+    // Borrow two different cells at once, called as `WithCell::with_two(&a, &b, f)`. Reading the
+    // same cell twice would be harmless, but it's almost certainly a bug at the call site, so this
+    // panics if the cells overlap.
+    #[track_caller]
+    pub fn with_two<B, U>(
+        a: &Self,
+        b: &WithCell<B, impl CellKind>,
+        f: impl FnOnce(&T, &B) -> U,
+    ) -> U {
+        assert!(
+            !a.borrow_entry(false).overlaps(&b.borrow_entry(false)),
+            "with_two cells overlap"
+        );
+        let a_ref = a.borrow();
+        let b_ref = b.borrow();
+        f(&a_ref, &b_ref)
+    }
+
+    // Get a cell for part of the value, for example a field. `f` must return a reference into the
+    // value itself, not into memory that it points to, and this panics otherwise. The new cell
+    // shares the borrow stack with the original, so borrowing one conflicts with borrowing the
+    // other. Borrows are tracked by address range, so cells for two different fields can be
+    // mutably borrowed at the same time, but two cells for the same field can't. The new cell is a
+    // View, so unlike a WithCell field, it can't be used while the original is borrowed.
+    /// # Safety
+    ///
+    /// The returned cell outlives any borrow, so setting the original cell can change what's at
+    /// the mapped address. `f` must return a field of `T`, or a field of a field, and so on, or an
+    /// element of an array. It must not return a reference into an enum variant's payload, since
+    /// setting the original cell to a different variant would leave the mapped cell pointing at
+    /// bytes of some other type. The range check rules out memory that `T` points to, like the
+    /// contents of a `Box` or a `Vec`, but it can't tell a field from a variant payload.
+    #[track_caller]
+    pub unsafe fn map<U>(&self, f: impl FnOnce(&T) -> &U) -> &WithCell<U, View> {
+        let base = self.0.get() as usize;
+        let address = self.with(|t| f(t) as *const U as usize);
+        assert!(
+            address >= base && address + mem::size_of::<U>() <= base + mem::size_of::<T>(),
+            "map must return a reference into the cell"
+        );
+        // Derive the new pointer from the UnsafeCell rather than from the &U, so that it's allowed
+        // to write.
+        unsafe { &*(self.0.get().cast::<u8>().add(address - base) as *const WithCell<U, View>) }
+    }
+
+    #[track_caller]
+    fn borrow_entry(&self, mutable: bool) -> BorrowEntry {
+        let start = self as *const Self as usize;
+        // Treat zero-sized cells as one byte long, so that they still conflict with themselves.
+        let len = cmp::max(mem::size_of::<T>(), 1);
+        BorrowEntry {
+            start,
+            end: start + len,
+            mutable,
+            location: Location::caller(),
+        }
+    }
+
+    // Check for conflicts and push the new entry under one access to the stack. A mutable borrow
+    // conflicts with any borrow of an overlapping cell, and a shared borrow only with mutable ones.
+    // On a conflict, this returns the conflicting entry, and the caller panics or gives up, after
+    // the stack is released.
+    #[track_caller]
+    fn try_push_borrow(&self, mutable: bool) -> Result<(), BorrowEntry> {
+        let entry = self.borrow_entry(mutable);
+        with_borrow_stack(|stack| {
+            let mut stack = stack.borrow_mut();
+            if let Some(conflict) = self.find_in(&stack, |e| mutable || e.mutable) {
+                return Err(conflict);
+            }
+            stack.push(entry);
+            Ok(())
         })
     }
 
-    fn assert_not_borrowed(&self) {
-        let self_address = self as *const Self as usize;
-        BORROW_STACK.with(|stack| {
-            let mut entry_ptr = stack.get();
-            while let Some(entry) = unsafe { entry_ptr.as_ref() } {
-                assert_ne!(self_address, entry.cell_address, "address is borrowed");
-                entry_ptr = entry.next;
+    fn pop_borrow(&self, mutable: bool) {
+        let entry = self.borrow_entry(mutable);
+        with_borrow_stack(|stack| {
+            let mut stack = stack.borrow_mut();
+            // Guards are usually dropped in the reverse order they were created, so the entry is
+            // usually the last one.
+            if stack.last().map_or(false, |last| last.same_borrow(&entry)) {
+                stack.pop();
+                return;
             }
+            let index = stack.iter().rposition(|e| e.same_borrow(&entry));
+            stack.remove(index.expect("borrow entry is missing"));
         });
     }
 
+    fn find_borrow(&self, pred: impl Fn(&BorrowEntry) -> bool) -> Option<BorrowEntry> {
+        with_borrow_stack(|stack| self.find_in(&stack.borrow(), pred))
+    }
+
+    fn find_in(
+        &self,
+        stack: &[BorrowEntry],
+        pred: impl Fn(&BorrowEntry) -> bool,
+    ) -> Option<BorrowEntry> {
+        let this = self.borrow_entry(false);
+        // An Owned cell strictly inside a borrowed one is a WithCell that the borrowed value
+        // contains, so it doesn't conflict. See Owned.
+        let nested = |entry: &BorrowEntry| !K::IS_VIEW && entry.strictly_contains(&this);
+        let entry = stack
+            .iter()
+            .rev()
+            .find(|entry| entry.overlaps(&this) && !nested(entry) && pred(entry));
+        entry.copied()
+    }
+
+    fn is_borrowed(&self) -> bool {
+        self.find_borrow(|_| true).is_some()
+    }
+
+    #[track_caller]
+    fn assert_not_borrowed(&self) {
+        if let Some(entry) = self.find_borrow(|_| true) {
+            panic_borrowed(entry);
+        }
+    }
+
+    fn is_mutably_borrowed(&self) -> bool {
+        self.find_borrow(|entry| entry.mutable).is_some()
+    }
+
+    #[track_caller]
+    fn assert_not_mutably_borrowed(&self) {
+        if let Some(entry) = self.find_borrow(|entry| entry.mutable) {
+            panic_mutably_borrowed(entry);
+        }
+    }
+
+    #[track_caller]
     pub fn replace(&self, t: T) -> T {
         self.assert_not_borrowed();
         unsafe { mem::replace(&mut *self.0.get(), t) }
     }
 
+    #[track_caller]
     pub fn set(&self, t: T) {
         self.replace(t);
     }
 
+    // Swapping a cell with itself does nothing, but cells from `map` can partially overlap, and
+    // swapping those panics.
+    #[track_caller]
     pub fn swap(&self, other: &Self) {
         if ptr::eq(self, other) {
             return;
         }
+        assert!(
+            !self
+                .borrow_entry(false)
+                .overlaps(&other.borrow_entry(false)),
+            "swap cells overlap"
+        );
         self.assert_not_borrowed();
         other.assert_not_borrowed();
         unsafe {
-            mem::swap(&mut *self.0.get(), &mut *other.0.get());
+            ptr::swap(self.0.get(), other.0.get());
+        }
+    }
+
+    // The value is moved out of the cell while `f` runs, so the cell is mutably borrowed, and any
+    // access to it from inside `f` panics. If `f` panics, there's no value to put back, so this
+    // aborts the process rather than leaving the cell empty. (Panicking in a destructor during
+    // unwinding aborts, and unlike std::process::abort, that works in no_std.)
+    #[track_caller]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.with_mut(|t| unsafe {
+            let abort_on_panic = OnDrop(|| panic!("WithCell::update closure panicked"));
+            ptr::write(t, f(ptr::read(t)));
+            mem::forget(abort_on_panic);
+        });
+    }
+
+    // Like AtomicUsize::fetch_update. If `f` returns None, the cell is left unchanged.
+    #[allow(clippy::result_unit_err)]
+    #[track_caller]
+    pub fn fetch_update(&self, f: impl FnOnce(&T) -> Option<T>) -> Result<T, ()> {
+        self.assert_not_borrowed();
+        match self.with(f) {
+            Some(new) => Ok(self.replace(new)),
+            None => Err(()),
+        }
+    }
+
+    // Like RefCell::replace_with. `f` can't take ownership of the old value, since we need to
+    // return it, so it gets a &mut T.
+    #[track_caller]
+    pub fn replace_with(&self, f: impl FnOnce(&mut T) -> T) -> T {
+        self.with_mut(|t| {
+            let new = f(t);
+            mem::replace(t, new)
+        })
+    }
+
+    // Install `temp` for the duration of `f`, and then put the original value back, even if `f`
+    // panics. The cell isn't borrowed while `f` runs, so `f` can read it or even set it, but
+    // whatever it sets gets replaced at the end.
+    #[track_caller]
+    pub fn scope<U>(&self, temp: T, f: impl FnOnce() -> U) -> U {
+        let mut original = Some(self.replace(temp));
+        let _restore = OnDrop(|| self.set(original.take().unwrap()));
+        f()
+    }
+
+    #[track_caller]
+    pub fn swap_field<U>(&self, project: impl FnOnce(&mut T) -> &mut U, other: &mut U) {
+        self.with_mut(|t| mem::swap(project(t), other));
+    }
+
+    // Swap two parts of the value, usually two fields, under one mutable borrow. Like map, the
+    // selectors must return references into the value itself, and this panics if they don't, or
+    // if the two references overlap. The swap goes through pointers derived from the UnsafeCell,
+    // since the first &mut U isn't usable after the second selector reborrows the value.
+    /// # Safety
+    ///
+    /// As with [`map`](Self::map), both selectors must return fields of `T`, or elements of an
+    /// array, and not references into an enum variant's payload. The second selector gets the
+    /// value mutably after the first one has returned, so it must also not change anything that
+    /// the first selector's reference depended on, like which variant an enum holds.
+    #[track_caller]
+    pub unsafe fn swap_fields<U>(
+        &self,
+        a: impl FnOnce(&mut T) -> &mut U,
+        b: impl FnOnce(&mut T) -> &mut U,
+    ) {
+        let _guard = self.borrow_mut();
+        let base = self.0.get();
+        let base_address = base as usize;
+        let offset = |address: usize| {
+            let base = base_address;
+            assert!(
+                address >= base && address + mem::size_of::<U>() <= base + mem::size_of::<T>(),
+                "swap_fields selectors must return references into the cell"
+            );
+            address - base
+        };
+        let offset_a = offset(a(unsafe { &mut *base }) as *mut U as usize);
+        let offset_b = offset(b(unsafe { &mut *base }) as *mut U as usize);
+        assert!(
+            offset_a != offset_b && offset_a.abs_diff(offset_b) >= mem::size_of::<U>(),
+            "swap_fields selectors overlap"
+        );
+        unsafe {
+            ptr::swap_nonoverlapping(
+                base.cast::<u8>().add(offset_a).cast::<U>(),
+                base.cast::<u8>().add(offset_b).cast::<U>(),
+                1,
+            );
+        }
+    }
+
+    pub fn try_replace(&self, t: T) -> Result<T, T> {
+        if self.is_borrowed() {
+            return Err(t);
+        }
+        Ok(self.replace(t))
+    }
+
+    pub fn try_set(&self, t: T) -> Result<(), T> {
+        self.try_replace(t).map(drop)
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn try_swap(&self, other: &Self) -> Result<(), ()> {
+        if ptr::eq(self, other) {
+            return Ok(());
         }
+        if self.is_borrowed() || other.is_borrowed() {
+            return Err(());
+        }
+        self.swap(other);
+        Ok(())
+    }
+}
+
+// Like map, but for every element at once. Each element cell covers its own address range, so
+// different elements can be borrowed at the same time, but borrowing an element conflicts with
+// borrowing the whole array.
+impl<T, K: CellKind, const N: usize> WithCell<[T; N], K> {
+    pub fn as_cells(&self) -> &[WithCell<T, View>; N] {
+        unsafe { &*(self as *const Self as *const [WithCell<T, View>; N]) }
     }
 }
 
-impl<T: Copy> WithCell<T> {
+impl<T: Copy, K: CellKind> WithCell<T, K> {
+    #[track_caller]
     pub fn get(&self) -> T {
+        self.assert_not_mutably_borrowed();
         unsafe { *self.0.get() }
     }
 }
 
-impl<T: Clone> WithCell<T> {
-    // It seems more useful to return T than to actually implement Clone and return WithCell<T>?
-    // Callers can convert between T and WithCell<T> freely, though, so it's not a huge deal either
-    // way. Feedback needed.
-    pub fn clone(&self) -> T {
+impl<T: Clone, K: CellKind> WithCell<T, K> {
+    // Like get, but for types that are Clone and not Copy.
+    #[track_caller]
+    pub fn get_cloned(&self) -> T {
         self.with(|t| t.clone())
     }
+
+    #[track_caller]
+    pub fn clone_inner(&self) -> T {
+        self.get_cloned()
+    }
+
+    // This used to be the only way to clone the value. The inherent method shadows Clone::clone
+    // in method calls, so with the clone feature enabled, call that one as `Clone::clone(&cell)`.
+    #[deprecated(note = "use `clone_inner` instead")]
+    #[allow(clippy::should_implement_trait)]
+    #[track_caller]
+    pub fn clone(&self) -> T {
+        self.clone_inner()
+    }
 }
 
-impl<T: Default> WithCell<T> {
-    pub fn take(&self) -> T {
-        self.replace(T::default())
+impl<T: Default + Clone, K: CellKind> WithCell<T, K> {
+    // Like get_cloned, but returns T::default() instead of panicking if the cell is mutably
+    // borrowed.
+    #[track_caller]
+    pub fn get_or_default(&self) -> T {
+        if self.is_mutably_borrowed() {
+            T::default()
+        } else {
+            self.get_cloned()
+        }
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+// This makes WithCell usable in #[derive(Clone)] structs. Use clone_inner to clone just the value.
+// It's behind the clone feature, because until the deprecated inherent clone method is removed,
+// `cell.clone()` still calls that one and returns a T.
+#[cfg(feature = "clone")]
+impl<T: Clone> Clone for WithCell<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.clone_inner())
+    }
+}
 
-    #[test]
-    fn test_set_get() {
-        let x = WithCell::new(0);
-        assert_eq!(0, x.get());
-        x.set(1);
-        assert_eq!(1, x.get());
+impl<T: Default, K: CellKind> WithCell<T, K> {
+    #[track_caller]
+    pub fn take(&self) -> T {
+        self.replace(T::default())
     }
 
-    #[test]
-    fn test_replace() {
-        let x = WithCell::new(0);
-        assert_eq!(0, x.replace(1));
-        assert_eq!(1, x.replace(2));
-        assert_eq!(2, x.into_inner());
+    // Like take, for when you want to move the value out of a `&WithCell` and assert that nothing
+    // is borrowed, the way `into_inner` would by construction. The panic message includes the
+    // location of the take_checked call, as well as where the conflicting borrow started.
+    #[track_caller]
+    pub fn take_checked(&self) -> T {
+        if let Some(entry) = self.find_borrow(|_| true) {
+            panic!(
+                "take_checked at {} while address is borrowed (borrow started at {})",
+                Location::caller(),
+                entry.location
+            );
+        }
+        unsafe { mem::take(&mut *self.0.get()) }
     }
 
-    #[test]
-    fn test_swap() {
-        let x = WithCell::new(0);
-        let y = WithCell::new(1);
-        x.swap(&y);
-        assert_eq!(1, x.get());
-        assert_eq!(0, y.get());
+    // Take the value out, leaving the default, and put it back when the returned guard is dropped.
+    // Unlike a borrow, this doesn't lock the cell in the meantime, and other code sees the
+    // default. If the value is moved out of the guard, the default that replaced it goes back.
+    #[track_caller]
+    pub fn checkout(&self) -> Checkout<'_, T, K> {
+        Checkout {
+            cell: self,
+            value: self.take(),
+        }
     }
 
-    #[test]
-    #[should_panic]
-    fn test_set_panic() {
-        let x = WithCell::new(0);
-        x.with(|_| {
-            x.set(1);
+    // Like update, `f` gets the value by move, and the cell is mutably borrowed while it runs. The
+    // difference is what happens if `f` panics: instead of aborting, this leaves `T::default()` in
+    // the cell, and the panic continues unwinding. The old value is dropped along with `f`.
+    #[track_caller]
+    pub fn map_in_place(&self, f: impl FnOnce(T) -> T) {
+        self.with_mut(|t| {
+            let old = mem::take(t);
+            *t = f(old);
         });
     }
 
-    #[test]
-    #[should_panic]
-    fn test_replace_panic() {
-        let x = WithCell::new(0);
-        x.with(|_| {
-            x.replace(1);
-        });
+    // The predicate and the take happen under one mutable borrow, so `pred` can't re-enter.
+    #[track_caller]
+    pub fn take_if(&self, pred: impl FnOnce(&T) -> bool) -> Option<T> {
+        self.with_mut(|t| if pred(t) { Some(mem::take(t)) } else { None })
     }
+}
 
-    #[test]
-    #[should_panic]
-    fn test_replace_panic_nested() {
-        let x = WithCell::new(0);
-        let y = WithCell::new(0);
-        let z = WithCell::new(0);
-        x.with(|_| {
-            y.with(|_| {
-                z.with(|_| {
-                    y.set(1);
-                });
-            });
-        });
+impl<T: PartialEq, K: CellKind> WithCell<T, K> {
+    // Replace the value if it equals `expected`, returning the old value, or give back `new` if it
+    // doesn't. The comparison and the write happen under one mutable borrow, so a PartialEq impl
+    // that touches the cell panics.
+    #[track_caller]
+    pub fn compare_and_set(&self, expected: &T, new: T) -> Result<T, T> {
+        self.with_mut(|t| {
+            if *t == *expected {
+                Ok(mem::replace(t, new))
+            } else {
+                Err(new)
+            }
+        })
     }
+}
 
-    #[test]
-    #[should_panic]
-    fn test_swap_panic_left() {
-        let x = WithCell::new(0);
-        x.with(|_| {
-            x.swap(&WithCell::new(1));
-        });
+// Shorthand for common operations on a WithCell<Vec<T>>.
+pub trait WithCellVecExt<T> {
+    // Replace the Vec with an empty one and return the old contents.
+    fn take_all(&self) -> Vec<T>;
+
+    fn push(&self, item: T);
+
+    // Clear the Vec and pass it to `f`, under a mutable borrow. Clearing keeps the allocation, so
+    // a cell used as a scratch buffer stops allocating once it's grown to its working size.
+    fn with_cleared<U>(&self, f: impl FnOnce(&mut Vec<T>) -> U) -> U;
+}
+
+impl<T, K: CellKind> WithCellVecExt<T> for WithCell<Vec<T>, K> {
+    #[track_caller]
+    fn take_all(&self) -> Vec<T> {
+        self.take()
     }
 
-    #[test]
-    #[should_panic]
-    fn test_swap_panic_right() {
-        let x = WithCell::new(0);
-        x.with(|_| {
-            WithCell::new(0).swap(&x);
-        });
+    #[track_caller]
+    fn push(&self, item: T) {
+        self.with_mut(|v| v.push(item));
     }
 
-    #[test]
-    fn test_swap_self_doesnt_panic() {
-        let x = WithCell::new(0);
-        x.with(|_| {
-            x.swap(&x);
-            assert_eq!(0, x.get());
+    #[track_caller]
+    fn with_cleared<U>(&self, f: impl FnOnce(&mut Vec<T>) -> U) -> U {
+        self.with_mut(|v| {
+            v.clear();
+            f(v)
+        })
+    }
+}
+
+// Shorthand for a WithCell<Box<T>>, which is a good way to hold a large value. The box moves in
+// and out of the cell, but the contents stay where they are on the heap.
+pub trait WithCellBoxExt<T: ?Sized> {
+    // Like replace, but moving only the box pointer, rather than whatever a large T would cost.
+    fn replace_boxed(&self, b: Box<T>) -> Box<T>;
+
+    // Like `with`, but `f` gets the contents of the box.
+    fn with_boxed<U>(&self, f: impl FnOnce(&T) -> U) -> U;
+}
+
+impl<T: ?Sized, K: CellKind> WithCellBoxExt<T> for WithCell<Box<T>, K> {
+    #[track_caller]
+    fn replace_boxed(&self, b: Box<T>) -> Box<T> {
+        self.replace(b)
+    }
+
+    #[track_caller]
+    fn with_boxed<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        self.with(|b| f(b))
+    }
+}
+
+// Shorthand for common operations on a WithCell<Option<T>>, which is a common way to hold lazily
+// initialized state. WithCell's own take already leaves None behind.
+pub trait WithCellOptionExt<T> {
+    // Store Some(v) and return the old value. WithCell's own replace takes an Option<T> instead.
+    fn replace_some(&self, v: T) -> Option<T>;
+
+    fn is_some(&self) -> bool;
+
+    // Store Some(v) if the cell is None, otherwise drop `v`.
+    fn get_or_insert(&self, v: T);
+
+    // If the cell is None, fill it with the result of `f`, and return a copy of the value either
+    // way. `f` runs while the cell is mutably borrowed, so it can't touch the cell.
+    fn get_or_insert_with(&self, f: impl FnOnce() -> T) -> T
+    where
+        T: Copy;
+}
+
+impl<T, K: CellKind> WithCellOptionExt<T> for WithCell<Option<T>, K> {
+    #[track_caller]
+    fn replace_some(&self, v: T) -> Option<T> {
+        self.with_mut(|option| option.replace(v))
+    }
+
+    // This only reads, so a shared borrow is enough, and it works inside `with`.
+    #[track_caller]
+    fn is_some(&self) -> bool {
+        self.with(Option::is_some)
+    }
+
+    #[track_caller]
+    fn get_or_insert(&self, v: T) {
+        self.with_mut(|option| {
+            option.get_or_insert(v);
         });
     }
 
-    #[test]
-    fn test_take() {
-        let x = WithCell::new(String::from("foo"));
-        x.with(|s| assert_eq!(s, "foo"));
-        assert_eq!(x.take(), "foo");
-        x.with(|s| assert_eq!(s, ""));
+    #[track_caller]
+    fn get_or_insert_with(&self, f: impl FnOnce() -> T) -> T
+    where
+        T: Copy,
+    {
+        self.with_mut(|option| *option.get_or_insert_with(f))
     }
+}
 
-    #[test]
+// Guards hold a &WithCell, and WithCell isn't Sync, so guards are neither Send nor Sync. That's
+// what keeps each borrow stack entry on the thread that pushed it, so that the guard pops it from
+// the same thread-local stack. The compile_fail tests at the bottom of this file check this.
+pub struct WithRef<'a, T, K: CellKind = Owned> {
+    cell: &'a WithCell<T, K>,
+}
+
+impl<T, K: CellKind> Deref for WithRef<'_, T, K> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.cell.0.get() }
+    }
+}
+
+impl<T, K: CellKind> Drop for WithRef<'_, T, K> {
+    fn drop(&mut self) {
+        self.cell.pop_borrow(false);
+    }
+}
+
+pub struct WithRefMut<'a, T, K: CellKind = Owned> {
+    cell: &'a WithCell<T, K>,
+}
+
+impl<T, K: CellKind> Deref for WithRefMut<'_, T, K> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.cell.0.get() }
+    }
+}
+
+impl<T, K: CellKind> DerefMut for WithRefMut<'_, T, K> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.cell.0.get() }
+    }
+}
+
+impl<T, K: CellKind> Drop for WithRefMut<'_, T, K> {
+    fn drop(&mut self) {
+        self.cell.pop_borrow(true);
+    }
+}
+
+pub struct Checkout<'a, T: Default, K: CellKind = Owned> {
+    cell: &'a WithCell<T, K>,
+    value: T,
+}
+
+impl<T: Default, K: CellKind> Deref for Checkout<'_, T, K> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Default, K: CellKind> DerefMut for Checkout<'_, T, K> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+// This panics if the cell is borrowed when the guard is dropped, like `set` would.
+impl<T: Default, K: CellKind> Drop for Checkout<'_, T, K> {
+    fn drop(&mut self) {
+        self.cell.set(mem::take(&mut self.value));
+    }
+}
+
+// A cell for one field of a WithCell, from `field_cell!(cell.a.b)`. It derefs to a View cell, like
+// the one `map` returns, whose borrows are tracked by the field's own address range. So two
+// different fields can be mutably borrowed at the same time, but borrowing the same field twice
+// panics, and so does borrowing the whole cell while one of its fields is borrowed.
+pub struct FieldCell<'a, T>(&'a WithCell<T, View>);
+
+impl<'a, T> FieldCell<'a, T> {
+    /// # Safety
+    ///
+    /// This is `map`, and it has the same requirements. `field_cell!` is the safe way to call it.
+    #[doc(hidden)]
+    #[track_caller]
+    pub unsafe fn __new<S, K: CellKind>(
+        cell: &'a WithCell<S, K>,
+        f: impl FnOnce(&S) -> &T,
+    ) -> Self {
+        FieldCell(cell.map(f))
+    }
+}
+
+impl<T> Clone for FieldCell<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for FieldCell<'_, T> {}
+
+impl<T> Deref for FieldCell<'_, T> {
+    type Target = WithCell<T, View>;
+
+    fn deref(&self) -> &WithCell<T, View> {
+        self.0
+    }
+}
+
+// Mutably borrow a WithCell and pass `f` a &mut to one of its fields, like
+// `with_field!(cell.a.b, |x| *x += 1)`. This is `with_mut` plus a field path, so the whole cell
+// is borrowed for exactly as long as `f` runs, and any other access to it from inside `f` panics.
+// The input can also be any expression in parentheses, like `with_field!((&cells[0]).a, f)`.
+#[macro_export]
+macro_rules! with_field {
+    ($cell:ident $(. $field:tt)+ , $f:expr $(,)?) => {
+        $crate::with_field!(($cell) $(. $field)+, $f)
+    };
+    (( $cell:expr ) $(. $field:tt)+ , $f:expr $(,)?) => {
+        $cell.with_mut(|value| {
+            $crate::__private_call_with_mut(&mut value $(. $field)+, $f)
+        })
+    };
+}
+
+// This gives the closure in with_field! its argument type, which calling it directly wouldn't.
+#[doc(hidden)]
+pub fn __private_call_with_mut<T: ?Sized, U>(value: &mut T, f: impl FnOnce(&mut T) -> U) -> U {
+    f(value)
+}
+
+// Get a FieldCell for a field of a WithCell, like `field_cell!(cell.a.b)`. This is the safe
+// version of `map`. The path can only name fields, so it can't lead into an enum variant's
+// payload, and each step is checked at compile time not to go through a Deref impl, which could
+// lead anywhere. The input can be any expression in parentheses that derefs to a WithCell,
+// including another FieldCell. Rust lexes chained tuple indices like `0.1` as one token, so they
+// need a space, like `field_cell!(cell.0 .1)`.
+#[macro_export]
+macro_rules! field_cell {
+    ($cell:ident $(. $field:tt)+) => {
+        $crate::field_cell!((&$cell) $(. $field)+)
+    };
+    (( $cell:expr ) $(. $field:tt)+) => {{
+        let cell: &$crate::WithCell<_, _> = $cell;
+        let project = $crate::__private::field_fn(cell, |value| {
+            $(
+                const _: () = $crate::__private::assert_single_field(stringify!($field));
+                $crate::__private::assert_no_deref(value, {
+                    #[allow(unused_imports)]
+                    use $crate::__private::{NotViaDeref as _, ViaDeref as _};
+                    (&$crate::__private::NoDeref::new(value)).check()
+                });
+                let value = &value.$field;
+            )+
+            value
+        });
+        // SAFETY: The closure only follows fields, and none of them through Deref.
+        #[allow(unused_unsafe)]
+        unsafe {
+            $crate::FieldCell::__new(cell, project)
+        }
+    }};
+}
+
+#[doc(hidden)]
+pub mod __private {
+    use super::{CellKind, WithCell};
+    use core::marker::PhantomData;
+
+    // This gives the closure in field_cell! its argument type.
+    pub fn field_fn<S, K: CellKind, U, F>(_cell: &WithCell<S, K>, f: F) -> F
+    where
+        F: FnOnce(&S) -> &U,
+    {
+        f
+    }
+
+    // This picks ViaDeref::check if T implements Deref, and NotViaDeref::check otherwise, which is
+    // autoref specialization. The Deref version returns a type that assert_no_deref rejects.
+    pub struct NoDeref<T: ?Sized>(PhantomData<*const T>);
+
+    impl<T: ?Sized> NoDeref<T> {
+        pub fn new(_value: &T) -> Self {
+            NoDeref(PhantomData)
+        }
+    }
+
+    pub struct ThroughDeref;
+
+    pub trait ViaDeref {
+        fn check(&self) -> ThroughDeref {
+            ThroughDeref
+        }
+    }
+
+    impl<T: ?Sized + core::ops::Deref> ViaDeref for NoDeref<T> {}
+
+    pub trait NotViaDeref {
+        fn check(&self) {}
+    }
+
+    impl<T: ?Sized> NotViaDeref for &NoDeref<T> {}
+
+    // The name of this trait is most of the error message when a path goes through Deref.
+    pub trait FieldCellCantGoThroughDeref<T: ?Sized> {}
+
+    impl<T: ?Sized> FieldCellCantGoThroughDeref<T> for () {}
+
+    pub fn assert_no_deref<T: ?Sized, C: FieldCellCantGoThroughDeref<T>>(_value: &T, _check: C) {}
+
+    // A float literal like `0.1` would be two field steps with no Deref check in between.
+    pub const fn assert_single_field(field: &str) {
+        let bytes = field.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'.' {
+                panic!("chained tuple indices in field_cell! need a space, like `.0 .1`");
+            }
+            i += 1;
+        }
+    }
+}
+
+impl<T: Default> Default for WithCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for WithCell<T> {
+    fn from(t: T) -> Self {
+        Self::new(t)
+    }
+}
+
+// Comparing a cell to itself doesn't need a special case, because shared borrows of the same cell
+// can nest.
+impl<T: PartialEq, K: CellKind> PartialEq for WithCell<T, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with(|a| other.with(|b| a == b))
+    }
+}
+
+impl<T: Eq, K: CellKind> Eq for WithCell<T, K> {}
+
+// Like PartialEq, comparing a cell to itself nests two shared borrows, which is fine. There's no
+// ptr::eq shortcut, because a value like f64::NAN isn't equal to itself.
+impl<T: PartialOrd, K: CellKind> PartialOrd for WithCell<T, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        self.with(|a| other.with(|b| a.partial_cmp(b)))
+    }
+}
+
+impl<T: Ord, K: CellKind> Ord for WithCell<T, K> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.with(|a| other.with(|b| a.cmp(b)))
+    }
+}
+
+// Hashing holds a shared borrow, so mutating the cell from inside a Hash impl panics.
+impl<T: Hash, K: CellKind> Hash for WithCell<T, K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.with(|t| t.hash(state));
+    }
+}
+
+// Shared borrows don't prevent reading the value, but a mutable borrow does. In that case print a
+// placeholder rather than panicking.
+impl<T: fmt::Debug, K: CellKind> fmt::Debug for WithCell<T, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct BorrowedPlaceholder;
+
+        impl fmt::Debug for BorrowedPlaceholder {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("<borrowed>")
+            }
+        }
+
+        let mut tuple = f.debug_tuple("WithCell");
+        if self.is_mutably_borrowed() {
+            tuple.field(&BorrowedPlaceholder);
+        } else {
+            self.with(|t| tuple.field(t));
+        }
+        tuple.finish()
+    }
+}
+
+// Serializing a cell that's mutably borrowed returns an error instead of panicking, so for example
+// saving state from inside a with_mut callback fails gracefully.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize, K: CellKind> serde::Serialize for WithCell<T, K> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_mutably_borrowed() {
+            return Err(serde::ser::Error::custom("WithCell is mutably borrowed"));
+        }
+        self.with(|t| t.serialize(serializer))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for WithCell<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
+// A WithCell that runs a callback with the new value after every set, replace, or update. The
+// mutation is finished before the callback runs, and the callback gets the value through a shared
+// borrow, so it can read the cell again, but trying to modify it from the callback panics, as does
+// replacing the callback from inside itself.
+pub struct ObservableWithCell<T> {
+    cell: WithCell<T>,
+    callback: WithCell<Option<Callback<T>>>,
+}
+
+type Callback<T> = Box<dyn FnMut(&T)>;
+
+impl<T> ObservableWithCell<T> {
+    pub fn new(t: T) -> Self {
+        Self {
+            cell: WithCell::new(t),
+            callback: WithCell::new(None),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.cell.into_inner()
+    }
+
+    // Replace any previous callback.
+    pub fn observe(&self, f: impl FnMut(&T) + 'static) {
+        self.callback.set(Some(Box::new(f)));
+    }
+
+    pub fn with<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        self.cell.with(f)
+    }
+
+    pub fn replace(&self, t: T) -> T {
+        let old = self.cell.replace(t);
+        self.notify();
+        old
+    }
+
+    pub fn set(&self, t: T) {
+        self.replace(t);
+    }
+
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.cell.update(f);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.callback.with_mut(|callback| {
+            if let Some(callback) = callback {
+                self.cell.with(|t| callback(t));
+            }
+        });
+    }
+}
+
+impl<T: Copy> ObservableWithCell<T> {
+    pub fn get(&self) -> T {
+        self.cell.get()
+    }
+}
+
+// A variant of WithCell that tracks borrows with a counter inside the cell, instead of the
+// thread-local borrow stack. That makes it Sync, at the cost of an atomic operation on every
+// access. Like WithCell, conflicting access panics rather than blocking, and that includes
+// conflicting access from another thread.
+pub struct AtomicWithCell<T> {
+    value: UnsafeCell<T>,
+    // The number of shared borrows, or WRITING if there's a mutable borrow.
+    state: AtomicUsize,
+}
+
+const WRITING: usize = usize::MAX;
+
+// Same bounds as RwLock: shared borrows on different threads give out &T concurrently, and
+// mutable borrows let any thread put in or take out a T.
+unsafe impl<T: Send + Sync> Sync for AtomicWithCell<T> {}
+
+impl<T> AtomicWithCell<T> {
+    pub fn new(t: T) -> Self {
+        Self {
+            value: UnsafeCell::new(t),
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn with<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            assert_ne!(state, WRITING, "AtomicWithCell is mutably borrowed");
+            assert_ne!(state + 1, WRITING, "too many shared borrows");
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => state = current,
+            }
+        }
+        let _on_drop = OnDrop(|| {
+            self.state.fetch_sub(1, Ordering::Release);
+        });
+        unsafe { f(&*self.value.get()) }
+    }
+
+    pub fn with_mut<U>(&self, f: impl FnOnce(&mut T) -> U) -> U {
+        let result = self
+            .state
+            .compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed);
+        assert!(result.is_ok(), "AtomicWithCell is borrowed");
+        let _on_drop = OnDrop(|| self.state.store(0, Ordering::Release));
+        unsafe { f(&mut *self.value.get()) }
+    }
+
+    pub fn is_borrowed(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn replace(&self, t: T) -> T {
+        self.with_mut(|value| mem::replace(value, t))
+    }
+
+    pub fn set(&self, t: T) {
+        self.replace(t);
+    }
+}
+
+impl<T: Copy> AtomicWithCell<T> {
+    pub fn get(&self) -> T {
+        self.with(|t| *t)
+    }
+}
+
+// These are doctests that are supposed to fail to compile, as in the cell_utils crate. A WithCell
+// can be sent to another thread when it's not borrowed, but it can't be shared, and its guards
+// can't be sent or shared.
+/// ```compile_fail,E0277
+/// use with_cell::WithCell;
+/// let x = WithCell::new(0);
+/// std::thread::scope(|s| {
+///     // FAIL: WithCell isn't Sync.
+///     s.spawn(|| x.get());
+/// });
+/// ```
+///
+/// ```compile_fail,E0277
+/// use with_cell::WithCell;
+/// fn assert_send<T: Send>(_: &T) {}
+/// let x = WithCell::new(0);
+/// // FAIL: WithRef isn't Send.
+/// assert_send(&x.borrow());
+/// ```
+///
+/// ```compile_fail,E0277
+/// use with_cell::WithCell;
+/// fn assert_sync<T: Sync>(_: &T) {}
+/// let x = WithCell::new(0);
+/// // FAIL: WithRef isn't Sync.
+/// assert_sync(&x.borrow());
+/// ```
+///
+/// ```compile_fail,E0277
+/// use with_cell::WithCell;
+/// let x = WithCell::new(0);
+/// std::thread::scope(|s| {
+///     let mut guard = x.borrow_mut();
+///     // FAIL: WithRefMut isn't Send.
+///     s.spawn(move || *guard += 1);
+/// });
+/// ```
+///
+/// ```compile_fail,E0277
+/// use with_cell::WithCell;
+/// fn assert_send<T: Send>(_: &T) {}
+/// let x = WithCell::new(0);
+/// // FAIL: Checkout isn't Send.
+/// assert_send(&x.checkout());
+/// ```
+fn _compile_fail_send_sync() {}
+
+// field_cell! only follows fields that are stored inline in the cell.
+/// ```compile_fail,E0277
+/// use with_cell::{field_cell, WithCell};
+/// let x = WithCell::new((Box::new((1, 2)),));
+/// // FAIL: x.0 is a Box.
+/// let second = field_cell!(x.0 .1);
+/// ```
+///
+/// ```compile_fail,E0133
+/// use with_cell::{field_cell, WithCell};
+/// union U {
+///     a: u32,
+///     b: f32,
+/// }
+/// let x = WithCell::new(U { a: 1 });
+/// // FAIL: Reading a union field is unsafe.
+/// let b = field_cell!(x.b);
+/// ```
+///
+/// ```compile_fail,E0080
+/// use with_cell::{field_cell, WithCell};
+/// let x = WithCell::new(((1, 2),));
+/// // FAIL: 0.1 is a single token.
+/// let second = field_cell!(x.0.1);
+/// ```
+fn _compile_fail_field_cell() {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_get() {
+        let x = WithCell::new(0);
+        assert_eq!(0, x.get());
+        x.set(1);
+        assert_eq!(1, x.get());
+    }
+
+    #[test]
+    fn test_replace() {
+        let x = WithCell::new(0);
+        assert_eq!(0, x.replace(1));
+        assert_eq!(1, x.replace(2));
+        assert_eq!(2, x.into_inner());
+    }
+
+    #[test]
+    fn test_swap() {
+        let x = WithCell::new(0);
+        let y = WithCell::new(1);
+        x.swap(&y);
+        assert_eq!(1, x.get());
+        assert_eq!(0, y.get());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.set(1);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_replace_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.replace(1);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_replace_panic_nested() {
+        let x = WithCell::new(0);
+        let y = WithCell::new(0);
+        let z = WithCell::new(0);
+        x.with(|_| {
+            y.with(|_| {
+                z.with(|_| {
+                    y.set(1);
+                });
+            });
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_swap_panic_left() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.swap(&WithCell::new(1));
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_swap_panic_right() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            WithCell::new(0).swap(&x);
+        });
+    }
+
+    #[test]
+    fn test_swap_self_doesnt_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.swap(&x);
+            assert_eq!(0, x.get());
+        });
+    }
+
+    #[test]
+    fn test_take() {
+        let x = WithCell::new(String::from("foo"));
+        x.with(|s| assert_eq!(s, "foo"));
+        assert_eq!(x.take(), "foo");
+        x.with(|s| assert_eq!(s, ""));
+    }
+
+    #[test]
     fn test_from_mut() {
         let mut s = String::from("foo");
         let c1 = WithCell::from_mut(&mut s);
@@ -209,4 +1480,1509 @@ mod test {
         c1.with(|s| assert_eq!(s, "bar"));
         assert_eq!(s, "bar");
     }
+
+    #[test]
+    fn test_with_mut() {
+        let x = WithCell::new(String::from("foo"));
+        let len = x.with_mut(|s| {
+            s.push_str("bar");
+            s.len()
+        });
+        assert_eq!(len, 6);
+        x.with(|s| assert_eq!(s, "foobar"));
+        // Borrowing other cells inside is fine.
+        let y = WithCell::new(1);
+        x.with_mut(|_| y.with_mut(|y| *y += 1));
+        assert_eq!(2, y.get());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_mut_panic_with() {
+        let x = WithCell::new(0);
+        x.with_mut(|_| {
+            x.with(|_| {});
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_mut_panic_nested() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.with_mut(|_| {});
+        });
+    }
+
+    #[test]
+    fn test_with_mut_fields() {
+        #[derive(Clone, Copy)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+        struct Entity {
+            position: Point,
+            velocity: Point,
+            log: Vec<&'static str>,
+        }
+        let entity = WithCell::new(Entity {
+            position: Point { x: 0, y: 0 },
+            velocity: Point { x: 1, y: 2 },
+            log: Vec::new(),
+        });
+        entity.with_mut(|e| {
+            let (position, velocity, log) = (&mut e.position, &e.velocity, &mut e.log);
+            position.x += velocity.x;
+            position.y += velocity.y;
+            log.push("moved");
+        });
+        let entity = entity.into_inner();
+        assert_eq!((1, 2), (entity.position.x, entity.position.y));
+        assert_eq!(vec!["moved"], entity.log);
+    }
+
+    #[test]
+    #[should_panic = "address is mutably borrowed"]
+    fn test_with_mut_fields_panic_get() {
+        #[derive(Clone, Copy)]
+        struct Counters {
+            a: i32,
+            b: i32,
+        }
+        let cell = WithCell::new(Counters { a: 0, b: 0 });
+        cell.with_mut(|s| {
+            s.a += 1;
+            s.b += cell.get().a;
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_mut_panic_get() {
+        let x = WithCell::new(0);
+        x.with_mut(|_| {
+            x.get();
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_mut_panic_set() {
+        let x = WithCell::new(0);
+        x.with_mut(|_| {
+            x.set(1);
+        });
+    }
+
+    #[test]
+    fn test_try_replace() {
+        let x = WithCell::new(0);
+        assert_eq!(Ok(0), x.try_replace(1));
+        x.with(|_| {
+            assert_eq!(Err(2), x.try_replace(2));
+            assert_eq!(Err(3), x.try_set(3));
+        });
+        assert_eq!(Ok(()), x.try_set(4));
+        assert_eq!(4, x.get());
+    }
+
+    #[test]
+    fn test_try_swap() {
+        let x = WithCell::new(0);
+        let y = WithCell::new(1);
+        x.with(|_| {
+            assert_eq!(Err(()), x.try_swap(&y));
+            assert_eq!(Err(()), y.try_swap(&x));
+            assert_eq!(Ok(()), x.try_swap(&x));
+        });
+        assert_eq!(Ok(()), x.try_swap(&y));
+        assert_eq!(1, x.get());
+        assert_eq!(0, y.get());
+    }
+
+    #[test]
+    fn test_update() {
+        let x = WithCell::new(1);
+        x.update(|n| n + 1);
+        x.update(|n| n * 10);
+        assert_eq!(20, x.get());
+
+        let s = WithCell::new(String::from("foo"));
+        s.update(|mut s| {
+            s.push_str("bar");
+            s
+        });
+        s.update(|s| s + "baz");
+        assert_eq!("foobarbaz", s.into_inner());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_update_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            x.update(|n| n + 1);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_get_mut() {
+        let depth = borrow_stack_depth();
+        let mut x = WithCell::new(String::from("foo"));
+        let s = x.get_mut();
+        s.push_str("bar");
+        // Nothing was pushed onto the borrow stack.
+        assert_eq!(depth, borrow_stack_depth());
+        s.push_str("baz");
+        assert_eq!("foobarbaz", x.into_inner());
+    }
+
+    #[test]
+    fn test_debug() {
+        let x = WithCell::new(vec![1, 2]);
+        assert_eq!("WithCell([1, 2])", format!("{:?}", x));
+        x.with(|_| assert_eq!("WithCell([1, 2])", format!("{:?}", x)));
+        x.with_mut(|v| {
+            v.push(3);
+            assert_eq!("WithCell(<borrowed>)", format!("{:?}", x));
+        });
+        assert_eq!("WithCell([1, 2, 3])", format!("{:?}", x));
+    }
+
+    #[test]
+    fn test_default_and_from() {
+        let x: WithCell<Vec<u8>> = Default::default();
+        x.with(|v| assert!(v.is_empty()));
+        let y: WithCell<i32> = 42.into();
+        assert_eq!(42, y.get());
+    }
+
+    #[test]
+    fn test_fetch_update() {
+        let x = WithCell::new(String::from("foo"));
+        let old = x.fetch_update(|s| Some(s.to_uppercase()));
+        assert_eq!(Ok(String::from("foo")), old);
+        let skipped = x.fetch_update(|s| {
+            assert_eq!(s, "FOO");
+            None
+        });
+        assert_eq!(Err(()), skipped);
+        x.with(|s| assert_eq!(s, "FOO"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_fetch_update_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| {
+            let _ = x.fetch_update(|_| None);
+        });
+    }
+
+    #[test]
+    fn test_borrow() {
+        let x = WithCell::new(String::from("foo"));
+        let y = WithCell::new(String::from("bar"));
+        let x_ref = x.borrow();
+        let y_ref = y.borrow();
+        assert_eq!(*x_ref, "foo");
+        assert_eq!(y_ref.len(), 3);
+        // Shared borrows can overlap, and guards can drop in any order.
+        let x_ref2 = x.borrow();
+        drop(x_ref);
+        assert!(x.try_set(String::new()).is_err());
+        drop(y_ref);
+        y.set(String::from("baz"));
+        drop(x_ref2);
+        x.set(String::from("qux"));
+        assert_eq!("qux", x.into_inner());
+        assert_eq!("baz", y.into_inner());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_borrow_panic_set() {
+        let x = WithCell::new(0);
+        let _guard = x.borrow();
+        x.set(1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_borrow_panic_with_mut() {
+        let x = WithCell::new(0);
+        x.with_mut(|_| {
+            x.borrow();
+        });
+    }
+
+    #[test]
+    fn test_atomic_with_cell() {
+        let x = AtomicWithCell::new(String::from("foo"));
+        x.with(|s| {
+            // Nested shared borrows are fine.
+            x.with(|s2| assert_eq!(s, s2));
+            assert!(x.is_borrowed());
+        });
+        assert!(!x.is_borrowed());
+        x.with_mut(|s| s.push_str("bar"));
+        assert_eq!("foobar", x.replace(String::from("baz")));
+        assert_eq!("baz", x.into_inner());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_atomic_with_cell_panic() {
+        let x = AtomicWithCell::new(0);
+        x.with(|_| x.set(1));
+    }
+
+    #[test]
+    fn test_atomic_with_cell_threads() {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let x = Arc::new(AtomicWithCell::new(0u64));
+        let barrier = Arc::new(Barrier::new(2));
+
+        // A mutable borrow on one thread makes access from another thread panic.
+        let handle = {
+            let x = Arc::clone(&x);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                x.with_mut(|n| {
+                    barrier.wait();
+                    *n = 1;
+                    barrier.wait();
+                });
+            })
+        };
+        barrier.wait();
+        assert!(x.is_borrowed());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| x.get()));
+        assert!(result.is_err());
+        barrier.wait();
+        handle.join().unwrap();
+        assert_eq!(1, x.get());
+
+        // Shared borrows on many threads at once are fine, and the count goes back to zero.
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let x = Arc::clone(&x);
+                thread::spawn(move || (0..1000).map(|_| x.with(|n| *n)).sum::<u64>())
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(1000, handle.join().unwrap());
+        }
+        assert!(!x.is_borrowed());
+        x.set(2);
+        assert_eq!(2, x.get());
+    }
+
+    #[test]
+    fn test_eq() {
+        let x = WithCell::new(String::from("foo"));
+        let y = WithCell::new(String::from("foo"));
+        let z = WithCell::new(String::from("bar"));
+        assert!(x == y);
+        assert!(x != z);
+        #[allow(clippy::eq_op)]
+        let x_eq_x = x == x;
+        assert!(x_eq_x);
+        x.with(|_| assert!(x == y));
+        let nan = WithCell::new(f64::NAN);
+        #[allow(clippy::eq_op)]
+        let nan_eq_nan = nan == nan;
+        assert!(!nan_eq_nan);
+    }
+
+    #[test]
+    fn test_swap_field() {
+        #[derive(Debug, PartialEq)]
+        struct Foo {
+            a: String,
+            b: String,
+        }
+        let x = WithCell::new(Foo {
+            a: String::from("a"),
+            b: String::from("b"),
+        });
+        let mut other = String::from("c");
+        x.swap_field(|foo| &mut foo.a, &mut other);
+        assert_eq!("a", other);
+        let expected = Foo {
+            a: String::from("c"),
+            b: String::from("b"),
+        };
+        assert_eq!(expected, x.into_inner());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_swap_field_panic() {
+        let x = WithCell::new((0, 0));
+        x.with(|_| x.swap_field(|t| &mut t.0, &mut 1));
+    }
+
+    #[test]
+    fn test_swap_fields() {
+        struct Foo {
+            a: String,
+            b: String,
+            c: [String; 2],
+        }
+        let x = WithCell::new(Foo {
+            a: String::from("a"),
+            b: String::from("b"),
+            c: [String::from("c0"), String::from("c1")],
+        });
+        unsafe { x.swap_fields(|foo| &mut foo.a, |foo| &mut foo.b) };
+        unsafe { x.swap_fields(|foo| &mut foo.c[1], |foo| &mut foo.a) };
+        let foo = x.into_inner();
+        assert_eq!("c1", foo.a);
+        assert_eq!("a", foo.b);
+        assert_eq!(["c0", "b"], foo.c);
+    }
+
+    #[test]
+    #[should_panic = "swap_fields selectors overlap"]
+    fn test_swap_fields_same_field() {
+        let x = WithCell::new((1, 2));
+        unsafe { x.swap_fields(|t| &mut t.0, |t| &mut t.0) };
+    }
+
+    #[test]
+    #[should_panic = "swap_fields selectors overlap"]
+    fn test_swap_fields_partial_overlap() {
+        use std::convert::TryInto;
+
+        let x = WithCell::new([1u8, 2, 3]);
+        unsafe {
+            x.swap_fields::<[u8; 2]>(
+                |t| (&mut t[0..2]).try_into().unwrap(),
+                |t| (&mut t[1..3]).try_into().unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic = "swap_fields selectors must return references into the cell"]
+    fn test_swap_fields_outside() {
+        let x = WithCell::new((1, Box::new(2)));
+        unsafe { x.swap_fields(|t| &mut t.0, |t| &mut *t.1) };
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_swap_fields_reentrant() {
+        let x = WithCell::new((1, 2));
+        x.with(|_| unsafe { x.swap_fields(|t| &mut t.0, |t| &mut t.1) });
+    }
+
+    #[test]
+    fn test_replace_with() {
+        let x = WithCell::new(String::from("foo"));
+        let old = x.replace_with(|s| s.repeat(2));
+        assert_eq!("foo", old);
+        assert_eq!("foofoo", x.into_inner());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_replace_with_panic() {
+        let x = WithCell::new(String::from("foo"));
+        x.replace_with(|s| {
+            x.set(String::new());
+            s.clone()
+        });
+    }
+
+    #[test]
+    fn test_map() {
+        struct Foo {
+            a: u64,
+            b: String,
+        }
+        let x = WithCell::new(Foo {
+            a: 1,
+            b: String::from("foo"),
+        });
+        let a = unsafe { x.map(|foo| &foo.a) };
+        let b = unsafe { x.map(|foo| &foo.b) };
+        a.set(2);
+        b.with_mut(|s| s.push_str("bar"));
+        // The two fields don't overlap, so borrowing one doesn't prevent setting the other.
+        a.with(|_| b.set(String::from("baz")));
+        let foo = x.into_inner();
+        assert_eq!(2, foo.a);
+        assert_eq!("baz", foo.b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_map_panic_parent_borrowed() {
+        let x = WithCell::new((0u64, 0u64));
+        let second = unsafe { x.map(|t| &t.1) };
+        x.with(|_| second.set(1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_map_panic_child_borrowed() {
+        let x = WithCell::new((0u64, 0u64));
+        let second = unsafe { x.map(|t| &t.1) };
+        second.with(|_| x.set((1, 1)));
+    }
+
+    #[test]
+    fn test_nested_cells() {
+        let outer = WithCell::new((0u64, WithCell::new(0u64)));
+        outer.with(|t| t.1.set(1));
+        outer.with_mut(|t| t.1.set(2));
+        assert_eq!(2, outer.into_inner().1.into_inner());
+        // A nested cell at the start of its parent has the same address, but it's still inside.
+        let outer = WithCell::new((WithCell::new(String::new()), 0u64));
+        outer.with(|t| t.0.set(String::from("foo")));
+        assert_eq!("foo", outer.into_inner().0.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_nested_cell_borrowed() {
+        let outer = WithCell::new((0u64, WithCell::new(String::new())));
+        outer.with(|t| t.1.with(|_| outer.set((1, WithCell::new(String::new())))));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_nested_cell_view() {
+        // A view of a nested cell is a View like any other, so it still conflicts.
+        let outer = WithCell::new((0u64, WithCell::new(0u64)));
+        let view = unsafe { outer.map(|t| &t.1) };
+        outer.with(|_| view.set(WithCell::new(1)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_map_panic_outside() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        unsafe { x.map(|v| &v[0]) };
+    }
+
+    #[test]
+    fn test_borrow_entry_overlaps() {
+        let entry = |start, end| BorrowEntry {
+            start,
+            end,
+            mutable: false,
+            location: Location::caller(),
+        };
+        assert!(entry(0, 8).overlaps(&entry(0, 8)));
+        assert!(entry(0, 8).overlaps(&entry(4, 12)));
+        assert!(entry(4, 12).overlaps(&entry(0, 8)));
+        assert!(entry(0, 8).overlaps(&entry(2, 4)));
+        assert!(entry(2, 4).overlaps(&entry(0, 8)));
+        // Adjacent ranges don't overlap.
+        assert!(!entry(0, 8).overlaps(&entry(8, 16)));
+        assert!(!entry(8, 16).overlaps(&entry(0, 8)));
+        assert!(!entry(0, 4).overlaps(&entry(12, 16)));
+    }
+
+    #[test]
+    fn test_overlapping_cells() {
+        let x = WithCell::new([[0u8; 4]; 2]);
+        let row0 = unsafe { x.map(|a| &a[0]) };
+        let row1 = unsafe { x.map(|a| &a[1]) };
+        let cell = unsafe { row1.map(|r| &r[3]) };
+        // Disjoint ranges don't conflict, even when they're adjacent.
+        row0.with(|_| row1.set([1; 4]));
+        row0.with(|_| cell.set(2));
+        // Overlapping ranges do, in both directions and at any depth.
+        x.with(|_| assert!(cell.try_set(3).is_err()));
+        cell.with(|_| assert!(x.try_set([[4; 4]; 2]).is_err()));
+        row1.with(|_| assert!(cell.try_set(5).is_err()));
+        cell.with(|_| assert!(row1.try_set([6; 4]).is_err()));
+        assert_eq!([[0, 0, 0, 0], [1, 1, 1, 2]], x.into_inner());
+    }
+
+    #[test]
+    fn test_zero_sized_cells() {
+        let x = WithCell::new(());
+        x.with(|_| assert!(x.try_set(()).is_err()));
+    }
+
+    #[test]
+    fn test_with_two() {
+        let x = WithCell::new(String::from("foo"));
+        let y = WithCell::new(3);
+        let s = WithCell::with_two(&x, &y, |s, n| {
+            assert!(x.try_set(String::new()).is_err());
+            assert!(y.try_set(0).is_err());
+            s.repeat(*n)
+        });
+        assert_eq!("foofoofoo", s);
+        x.set(String::from("bar"));
+        y.set(2);
+        assert_eq!("barbar", WithCell::with_two(&x, &y, |s, n| s.repeat(*n)));
+    }
+
+    #[test]
+    #[should_panic = "with_two cells overlap"]
+    fn test_with_two_panic() {
+        let x = WithCell::new(0);
+        WithCell::with_two(&x, &x, |_, _| {});
+    }
+
+    #[test]
+    fn test_take_if() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        assert_eq!(None, x.take_if(|v| v.is_empty()));
+        x.with(|v| assert_eq!(v, &[1, 2, 3]));
+        assert_eq!(Some(vec![1, 2, 3]), x.take_if(|v| v.len() == 3));
+        x.with(|v| assert!(v.is_empty()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_take_if_panic() {
+        let x = WithCell::new(0);
+        x.take_if(|_| x.get() == 0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_clone() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        let v: Vec<i32> = x.clone();
+        assert_eq!(vec![1, 2, 3], v);
+    }
+
+    #[test]
+    #[cfg(feature = "clone")]
+    fn test_clone() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        let y = Clone::clone(&x);
+        x.with_mut(|v| v.push(4));
+        y.with(|v| assert_eq!(v, &[1, 2, 3]));
+        assert_eq!(vec![1, 2, 3, 4], x.clone_inner());
+
+        #[derive(Clone)]
+        struct Foo {
+            v: WithCell<Vec<i32>>,
+        }
+        let foo = Foo { v: y };
+        let foo2 = foo.clone();
+        foo.v.set(Vec::new());
+        assert_eq!(vec![1, 2, 3], foo2.v.into_inner());
+    }
+
+    #[test]
+    fn test_as_ptr() {
+        let x = WithCell::new(42);
+        assert_eq!(x.get(), unsafe { *x.as_ptr() });
+        let y = unsafe { WithCell::from_ptr(x.as_ptr()) };
+        assert!(ptr::eq(&x, y));
+        y.set(43);
+        assert_eq!(43, x.get());
+
+        let mut n = 5;
+        let z = unsafe { WithCell::from_ptr(&mut n) };
+        z.set(6);
+        assert_eq!(6, n);
+    }
+
+    // Without the std feature, other tests running in parallel share the stack.
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_assert_borrow_stack_empty() {
+        assert_borrow_stack_empty();
+        let x = WithCell::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            x.with(|_| x.with(|_| panic!("oops")));
+        }));
+        assert!(result.is_err());
+        assert_borrow_stack_empty();
+    }
+
+    // The guard stands in for a leaked one, but unlike a leaked guard, it pops its entry when the
+    // panic unwinds, so it doesn't leave anything on the stack for other tests.
+    #[test]
+    #[should_panic(expected = "borrow stack isn't empty")]
+    fn test_assert_borrow_stack_empty_panic() {
+        let x = WithCell::new(0);
+        let _guard = x.borrow();
+        assert_borrow_stack_empty();
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn test_hash() {
+        use std::collections::HashMap;
+
+        let mut map = HashMap::new();
+        map.insert(WithCell::new(String::from("foo")), 1);
+        map.insert(WithCell::new(String::from("bar")), 2);
+        assert_eq!(Some(&1), map.get(&WithCell::new(String::from("foo"))));
+        assert_eq!(Some(&2), map.get(&WithCell::new(String::from("bar"))));
+        assert_eq!(None, map.get(&WithCell::new(String::from("baz"))));
+    }
+
+    #[test]
+    fn test_with_or() {
+        let x = WithCell::new(5);
+        assert_eq!(5, x.with_or(0, |n| *n));
+        x.with(|_| assert_eq!(5, x.with_or(0, |n| *n)));
+        x.with_mut(|n| {
+            *n = 6;
+            assert_eq!(0, x.with_or(0, |n| *n));
+        });
+        assert_eq!(6, x.with_or(0, |n| *n));
+    }
+
+    #[test]
+    fn test_scope() {
+        let x = WithCell::new(String::from("foo"));
+        let len = x.scope(String::from("temp"), || {
+            x.with(|s| assert_eq!(s, "temp"));
+            x.set(String::from("changed"));
+            x.with(|s| s.len())
+        });
+        assert_eq!(7, len);
+        x.with(|s| assert_eq!(s, "foo"));
+    }
+
+    #[test]
+    fn test_scope_panic() {
+        let x = WithCell::new(1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            x.scope(2, || {
+                assert_eq!(2, x.get());
+                panic!("oops");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(1, x.get());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scope_panic_borrowed() {
+        let x = WithCell::new(1);
+        x.with(|_| x.scope(2, || {}));
+    }
+
+    #[test]
+    fn test_get_cloned() {
+        let x = WithCell::new(String::from("foo"));
+        let s = x.get_cloned();
+        x.set(String::from("bar"));
+        assert_eq!("foo", s);
+        assert_eq!("bar", x.get_cloned());
+        // get is only for Copy types, but get_cloned works for those too.
+        let y = WithCell::new(5);
+        assert_eq!(y.get(), y.get_cloned());
+        // Reading during a shared borrow is fine.
+        x.with(|_| assert_eq!("bar", x.get_cloned()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_cloned_mutably_borrowed() {
+        let x = WithCell::new(String::from("foo"));
+        x.with_mut(|_| x.get_cloned());
+    }
+
+    #[test]
+    fn test_get_or_default() {
+        let x = WithCell::new(String::from("foo"));
+        assert_eq!("foo", x.get_or_default());
+        x.with(|_| assert_eq!("foo", x.get_or_default()));
+        x.with_mut(|_| assert_eq!("", x.get_or_default()));
+    }
+
+    #[test]
+    #[should_panic = "swap cells overlap"]
+    fn test_swap_overlapping() {
+        use std::convert::TryInto;
+        let x = WithCell::new([1u8, 2, 3]);
+        let front: &WithCell<[u8; 2], View> = unsafe { x.map(|a| a[..2].try_into().unwrap()) };
+        let back: &WithCell<[u8; 2], View> = unsafe { x.map(|a| a[1..].try_into().unwrap()) };
+        front.swap(back);
+    }
+
+    #[test]
+    fn test_swap_disjoint_views() {
+        use std::convert::TryInto;
+        let x = WithCell::new([1u8, 2, 3, 4]);
+        let front: &WithCell<[u8; 2], View> = unsafe { x.map(|a| a[..2].try_into().unwrap()) };
+        let back: &WithCell<[u8; 2], View> = unsafe { x.map(|a| a[2..].try_into().unwrap()) };
+        front.swap(back);
+        assert_eq!([3, 4, 1, 2], x.get());
+    }
+
+    #[test]
+    fn test_cell_conversions() {
+        let x = WithCell::new(String::from("foo"));
+        let cell = x.into_cell();
+        assert_eq!("foo", cell.take());
+        cell.set(String::from("bar"));
+        let x = WithCell::from_cell(cell);
+        assert_eq!("bar", x.into_inner());
+
+        let x = WithCell::new(5);
+        let cell = unsafe { x.as_cell() };
+        cell.set(6);
+        assert_eq!(6, x.get());
+        let y = unsafe { WithCell::from_cell_ref(cell) };
+        assert!(ptr::eq(&x, y));
+    }
+
+    #[test]
+    fn test_cell_conversions_array_of_cells() {
+        let x = WithCell::new([1, 2, 3]);
+        let cell = x.into_cell();
+        let cells = cell_utils::array_of_cells(&cell);
+        cells[1].set(20);
+        let x = WithCell::from_cell(cell);
+        assert_eq!([1, 20, 3], x.get());
+    }
+
+    #[test]
+    fn test_panic_message_location() {
+        fn panic_message(f: impl FnOnce()) -> String {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+            *result.unwrap_err().downcast::<String>().unwrap()
+        }
+        // The location should be of the `with` call in this file, followed by a line number.
+        fn borrow_line(message: &str) -> u32 {
+            let prefix = format!("(borrow started at {}:", file!());
+            let rest = &message[message.find(&prefix).expect(message) + prefix.len()..];
+            rest.split(':').next().unwrap().parse().unwrap()
+        }
+
+        let x = WithCell::new(1);
+        let message = panic_message(|| x.with(|_| x.set(2)));
+        assert!(message.starts_with("address is borrowed"), "{}", message);
+        assert_eq!(line!() - 2, borrow_line(&message));
+
+        let message = panic_message(|| {
+            x.with_mut(|_| x.get());
+        });
+        assert!(message.starts_with("address is mutably borrowed"));
+        assert_eq!(line!() - 3, borrow_line(&message));
+    }
+
+    #[test]
+    fn test_map_in_place() {
+        struct MoveOnly(Vec<i32>);
+        impl Default for MoveOnly {
+            fn default() -> Self {
+                MoveOnly(vec![-1])
+            }
+        }
+
+        let x = WithCell::new(MoveOnly(vec![1, 2]));
+        x.map_in_place(|MoveOnly(mut v)| {
+            v.push(3);
+            MoveOnly(v)
+        });
+        assert_eq!(vec![1, 2, 3], x.with(|m| m.0.clone()));
+
+        // A panic in the closure leaves the default, and the cell isn't left borrowed.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            x.map_in_place(|_| panic!("oops"));
+        }));
+        assert!(result.is_err());
+        assert_eq!(vec![-1], x.with(|m| m.0.clone()));
+        x.set(MoveOnly(vec![4]));
+        assert_eq!(vec![4], x.into_inner().0);
+    }
+
+    #[test]
+    #[should_panic(expected = "address is mutably borrowed")]
+    fn test_map_in_place_reentrant() {
+        let x = WithCell::new(1);
+        x.map_in_place(|n| n + x.get());
+    }
+
+    #[test]
+    fn test_send() {
+        let x = WithCell::new(vec![1]);
+        x.push(2);
+        let x = std::thread::spawn(move || {
+            x.push(3);
+            x
+        })
+        .join()
+        .unwrap();
+        assert_eq!(vec![1, 2, 3], x.into_inner());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_with_result() {
+        fn parse(x: &WithCell<String>) -> Result<i32, std::num::ParseIntError> {
+            let n = x.with_result(|s| s.parse::<i32>())?;
+            Ok(n * 2)
+        }
+        let depth = borrow_stack_depth();
+        let x = WithCell::new(String::from("21"));
+        assert_eq!(Ok(42), parse(&x));
+        x.set(String::from("nope"));
+        assert!(parse(&x).is_err());
+        // The borrow was released, so the cell can be modified again.
+        assert_eq!(depth, borrow_stack_depth());
+        x.set(String::from("1"));
+        let result: Result<(), &str> = x.with_result(|s| {
+            assert_eq!("1", s);
+            x.with_result(|_| Err("inner"))
+        });
+        assert_eq!(Err("inner"), result);
+        x.with_mut(|s| s.push('0'));
+        assert_eq!(Ok(20), parse(&x));
+    }
+
+    #[test]
+    fn test_with_field() {
+        struct Inner {
+            count: i32,
+            names: Vec<&'static str>,
+        }
+        struct Outer {
+            inner: Inner,
+            pair: (i32, (i32, i32)),
+        }
+        let x = WithCell::new(Outer {
+            inner: Inner {
+                count: 0,
+                names: Vec::new(),
+            },
+            pair: (1, (2, 3)),
+        });
+        with_field!(x.inner.count, |count| *count += 1);
+        with_field!(x.inner.names, |names| names.push("a"));
+        let len = with_field!((&x).inner.names, |names: &mut Vec<_>| names.len());
+        assert_eq!(1, len);
+        with_field!(x.pair.1 .0, |n| *n *= 10);
+        let outer = x.into_inner();
+        assert_eq!(1, outer.inner.count);
+        assert_eq!(vec!["a"], outer.inner.names);
+        assert_eq!((1, (20, 3)), outer.pair);
+    }
+
+    #[test]
+    fn test_with_field_reentrant() {
+        let x = WithCell::new((1, 2));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_field!(x.0, |a| *a += x.get().1);
+        }));
+        assert!(result.is_err());
+        // The borrow ended with the closure, so the cell is usable again.
+        with_field!(x.1, |b| *b += 1);
+        assert_eq!((1, 3), x.get());
+    }
+
+    #[test]
+    fn test_checkout() {
+        let x = WithCell::new(vec![1, 2]);
+        {
+            let mut checkout = x.checkout();
+            assert_eq!(Vec::<i32>::new(), x.get_cloned());
+            checkout.push(3);
+            // The cell isn't borrowed, so it can be used while the value is checked out.
+            x.push(99);
+            assert_eq!(vec![1, 2, 3], *checkout);
+        }
+        assert_eq!(vec![1, 2, 3], x.get_cloned());
+
+        // The value can be put back in a different scope than it was taken in.
+        let checkouts = vec![x.checkout()];
+        assert!(x.get_cloned().is_empty());
+        drop(checkouts);
+        assert_eq!(vec![1, 2, 3], x.get_cloned());
+
+        // Taking the value out of the checkout puts back the default.
+        let mut checkout = x.checkout();
+        let taken = mem::take(&mut *checkout);
+        x.set(vec![4]);
+        drop(checkout);
+        assert_eq!(vec![1, 2, 3], taken);
+        assert_eq!(Vec::<i32>::new(), x.get_cloned());
+    }
+
+    #[test]
+    fn test_take_checked() {
+        let x = WithCell::new(vec![1, 2]);
+        assert_eq!(vec![1, 2], x.take_checked());
+        assert_eq!(Vec::<i32>::new(), x.get_cloned());
+
+        x.set(vec![3]);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            x.with(|_| x.take_checked());
+        }));
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        let caller = format!("take_checked at {}:{}:", file!(), line!() - 3);
+        assert!(message.starts_with(&caller), "{}", message);
+        assert!(message.contains("(borrow started at"), "{}", message);
+        // The value wasn't taken.
+        assert_eq!(vec![3], x.get_cloned());
+    }
+
+    #[test]
+    fn test_ord() {
+        let mut v: Vec<WithCell<i32>> = vec![3, 1, 2].into_iter().map(WithCell::new).collect();
+        v.sort();
+        let v: Vec<i32> = v.into_iter().map(WithCell::into_inner).collect();
+        assert_eq!(vec![1, 2, 3], v);
+
+        let (a, b) = (WithCell::new(1), WithCell::new(2));
+        assert!(a < b);
+        assert_eq!(cmp::Ordering::Greater, b.cmp(&a));
+        assert_eq!(cmp::Ordering::Equal, a.cmp(&a));
+        a.with(|_| assert!(a <= a));
+
+        let nan = WithCell::new(f64::NAN);
+        assert_eq!(None, nan.partial_cmp(&nan));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_ord_mutably_borrowed() {
+        let (a, b) = (WithCell::new(1), WithCell::new(2));
+        a.with_mut(|_| a < b);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_borrow_mut() {
+        let depth = borrow_stack_depth();
+        let x = WithCell::new(vec![1, 2]);
+        {
+            let mut guard = x.borrow_mut();
+            guard.push(3);
+            assert_eq!(3, guard.len());
+        }
+        assert_eq!(vec![1, 2, 3], x.get_cloned());
+        // Guards for different cells can be dropped in any order.
+        let y = WithCell::new(0);
+        let guard_x = x.borrow_mut();
+        let mut guard_y = y.borrow_mut();
+        drop(guard_x);
+        *guard_y += 1;
+        x.set(Vec::new());
+        drop(guard_y);
+        assert_eq!(1, y.get());
+        assert_eq!(depth, borrow_stack_depth());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_borrow_mut_while_borrowed() {
+        let x = WithCell::new(0);
+        let _guard = x.borrow();
+        x.borrow_mut();
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_borrow_mut_twice() {
+        let x = WithCell::new(0);
+        let _guard = x.borrow_mut();
+        x.borrow_mut();
+    }
+
+    #[test]
+    fn test_borrow_mut_reentrancy() {
+        let x = WithCell::new(0);
+        let _guard = x.borrow_mut();
+        let panics =
+            |f: &dyn Fn()| std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err();
+        assert!(panics(&|| x.with(|_| {})));
+        assert!(panics(&|| x.set(1)));
+        assert!(panics(&|| drop(x.borrow())));
+        assert!(panics(&|| {
+            x.get();
+        }));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct State {
+            name: String,
+            values: WithCell<Vec<i32>>,
+        }
+        let state = State {
+            name: String::from("foo"),
+            values: WithCell::new(vec![1, 2, 3]),
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(r#"{"name":"foo","values":[1,2,3]}"#, json);
+        let state: State = serde_json::from_str(&json).unwrap();
+        assert_eq!("foo", state.name);
+        assert_eq!(vec![1, 2, 3], state.values.into_inner());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde_mutably_borrowed() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        x.with(|_| assert_eq!("[1,2,3]", serde_json::to_string(&x).unwrap()));
+        let error = x.with_mut(|_| serde_json::to_string(&x).unwrap_err());
+        assert!(error.to_string().contains("mutably borrowed"), "{}", error);
+    }
+
+    #[test]
+    fn test_leak() {
+        let config: &'static mut Vec<&str> = Box::new(WithCell::new(vec!["a"])).leak();
+        config.push("b");
+        assert_eq!(vec!["a", "b"], *config);
+
+        // The lifetime can also be shorter than 'static.
+        let s = String::from("foo");
+        let leaked: &mut &str = Box::new(WithCell::new(s.as_str())).leak();
+        *leaked = "bar";
+        assert_eq!("bar", *leaked);
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        #[derive(Debug, PartialEq)]
+        enum State {
+            Idle,
+            Running(u32),
+        }
+        let x = WithCell::new(State::Idle);
+        assert_eq!(
+            Ok(State::Idle),
+            x.compare_and_set(&State::Idle, State::Running(1))
+        );
+        assert_eq!(
+            Err(State::Running(2)),
+            x.compare_and_set(&State::Idle, State::Running(2))
+        );
+        assert_eq!(
+            Ok(State::Running(1)),
+            x.compare_and_set(&State::Running(1), State::Idle)
+        );
+        assert_eq!(State::Idle, x.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_compare_and_set_panic() {
+        let x = WithCell::new(0);
+        x.with(|_| x.compare_and_set(&0, 1)).unwrap();
+    }
+
+    #[test]
+    #[should_panic = "address is mutably borrowed"]
+    fn test_compare_and_set_reentrant_eq() {
+        struct Reentrant(i32);
+        thread_local! {
+            static CELL: WithCell<Reentrant> = WithCell::new(Reentrant(0));
+        }
+        // Comparing reads the cell, which is mutably borrowed during compare_and_set.
+        impl PartialEq for Reentrant {
+            fn eq(&self, other: &Self) -> bool {
+                CELL.with(|cell| cell.with(|_| self.0 == other.0))
+            }
+        }
+        CELL.with(|cell| {
+            let _ = cell.compare_and_set(&Reentrant(0), Reentrant(1));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_unchecked_paths() {
+        let depth = borrow_stack_depth();
+        let stack_len = || borrow_stack_depth() - depth;
+
+        let checked = WithCell::new(0);
+        let mut unchecked = WithCell::new(0);
+        for i in 0..10 {
+            checked.set(i);
+            unchecked.set_unique(i);
+        }
+        assert_eq!(checked.get(), unchecked.get());
+
+        let checked_depth = checked.with_mut(|t| {
+            *t += 1;
+            stack_len()
+        });
+        let unchecked_depth = unsafe {
+            unchecked.with_mut_unchecked(|t| {
+                *t += 1;
+                stack_len()
+            })
+        };
+        assert_eq!(checked.get(), unchecked.get());
+        // Only the checked path pushed an entry.
+        assert_eq!(1, checked_depth);
+        assert_eq!(0, unchecked_depth);
+        assert_eq!(0, unsafe { unchecked.with_unchecked(|_| stack_len()) });
+        assert_eq!(10, *unchecked.get_mut());
+    }
+
+    #[test]
+    fn test_vec_ext() {
+        let x = WithCell::new(Vec::new());
+        x.push(1);
+        x.push(2);
+        assert_eq!(vec![1, 2], x.take_all());
+        assert!(x.take_all().is_empty());
+        x.push(3);
+        assert_eq!(vec![3], x.into_inner());
+    }
+
+    #[test]
+    fn test_vec_ext_with_cleared() {
+        let scratch = WithCell::new(Vec::with_capacity(16));
+        let capacity = scratch.with(|v| v.capacity());
+        let ptr = scratch.with(|v| v.as_ptr());
+        for i in 0..10u8 {
+            let sum: u32 = scratch.with_cleared(|v| {
+                assert!(v.is_empty());
+                v.extend(0..=i);
+                v.iter().map(|&x| x as u32).sum()
+            });
+            assert_eq!((0..=i as u32).sum::<u32>(), sum);
+            // The buffer is reused, not reallocated.
+            assert_eq!(capacity, scratch.with(|v| v.capacity()));
+            assert_eq!(ptr, scratch.with(|v| v.as_ptr()));
+        }
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9], scratch.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_vec_ext_with_cleared_panic() {
+        let x = WithCell::new(vec![1]);
+        x.with_cleared(|v| v.push(x.take_all().len()));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_vec_ext_push_panic() {
+        let x = WithCell::new(vec![1]);
+        x.with(|v| x.push(v[0]));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_vec_ext_take_all_panic() {
+        let x = WithCell::new(vec![1]);
+        x.with(|_| x.take_all());
+    }
+
+    #[test]
+    fn test_box_ext() {
+        let first = Box::new([1u8; 4096]);
+        let first_ptr: *const [u8; 4096] = &*first;
+        let x = WithCell::new(first);
+        assert_eq!(first_ptr, x.with_boxed(|array| array as *const _));
+        assert_eq!(
+            4096,
+            x.with_boxed(|array| array.iter().map(|&b| b as usize).sum::<usize>())
+        );
+
+        let second = Box::new([2u8; 4096]);
+        let second_ptr: *const [u8; 4096] = &*second;
+        // Only the pointers move. The old box comes back with its contents in place.
+        let old = x.replace_boxed(second);
+        assert_eq!(first_ptr, &*old as *const _);
+        assert_eq!(second_ptr, x.with_boxed(|array| array as *const _));
+        assert_eq!([2; 4096], **x.borrow());
+
+        // Unsized contents work too.
+        let s: WithCell<Box<str>> = WithCell::new("abc".into());
+        assert_eq!(3, s.with_boxed(str::len));
+        assert_eq!("abc", &*s.replace_boxed("de".into()));
+        assert_eq!("de", &*s.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_box_ext_replace_boxed_panic() {
+        let x = WithCell::new(Box::new(1));
+        x.with_boxed(|_| x.replace_boxed(Box::new(2)));
+    }
+
+    #[test]
+    fn test_option_ext_get_or_insert_with() {
+        let x = WithCell::new(None);
+        assert_eq!(1, x.get_or_insert_with(|| 1));
+        assert_eq!(Some(1), x.get());
+        // Already present, so the closure doesn't run.
+        assert_eq!(1, x.get_or_insert_with(|| unreachable!()));
+        assert_eq!(Some(1), x.into_inner());
+    }
+
+    #[test]
+    fn test_option_ext_lifecycle() {
+        let x: WithCell<Option<String>> = WithCell::new(None);
+        assert!(!x.is_some());
+        x.get_or_insert("first".to_string());
+        x.get_or_insert("ignored".to_string());
+        assert!(x.with(|_| x.is_some()));
+        let old = x.replace_some("second".to_string());
+        assert_eq!(Some("first".to_string()), old);
+        assert_eq!(Some("second".to_string()), x.take());
+        assert!(!x.is_some());
+        assert_eq!(None, x.take());
+        x.get_or_insert("third".to_string());
+        assert_eq!(Some("third".to_string()), x.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_option_ext_take_panic() {
+        let x = WithCell::new(Some(1));
+        x.with(|_| x.take());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_option_ext_replace_some_panic() {
+        let x = WithCell::new(Some(1));
+        x.with(|_| x.replace_some(2));
+    }
+
+    #[test]
+    #[should_panic = "address is mutably borrowed"]
+    fn test_option_ext_get_or_insert_with_panic() {
+        let x = WithCell::new(None::<i32>);
+        x.get_or_insert_with(|| x.get().unwrap_or(0));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_borrow_stack_depth() {
+        let depth = borrow_stack_depth();
+        assert_eq!(depth > 0, is_any_borrow_active());
+        let x = WithCell::new(0);
+        let y = WithCell::new(0);
+        x.with(|_| {
+            assert_eq!(depth + 1, borrow_stack_depth());
+            assert!(is_any_borrow_active());
+            x.with(|_| {
+                y.with_mut(|_| assert_eq!(depth + 3, borrow_stack_depth()));
+                assert_eq!(depth + 2, borrow_stack_depth());
+            });
+        });
+        assert_eq!(depth, borrow_stack_depth());
+        let guard = y.borrow_mut();
+        assert_eq!(depth + 1, borrow_stack_depth());
+        drop(guard);
+        assert_eq!(depth, borrow_stack_depth());
+    }
+
+    #[test]
+    fn test_observable() {
+        use alloc::rc::Rc;
+
+        let calls = Rc::new(WithCell::new(Vec::new()));
+        let x = ObservableWithCell::new(0);
+        x.set(1);
+        let calls2 = calls.clone();
+        x.observe(move |&t| calls2.push(t));
+        x.set(2);
+        assert_eq!(2, x.replace(3));
+        x.update(|t| t * 10);
+        assert_eq!(30, x.get());
+        assert_eq!(vec![2, 3, 30], calls.take_all());
+        x.observe(|_| {});
+        x.set(4);
+        assert!(calls.take_all().is_empty());
+    }
+
+    #[test]
+    fn test_observable_reads_new_value() {
+        use alloc::rc::Rc;
+
+        let x = Rc::new(ObservableWithCell::new(String::new()));
+        let seen = Rc::new(WithCell::new(Vec::new()));
+        let (x2, seen2) = (Rc::downgrade(&x), seen.clone());
+        x.observe(move |new| {
+            // Reading the cell again from the callback is allowed, and it has the new value.
+            let x = x2.upgrade().unwrap();
+            x.with(|s| assert_eq!(s, new));
+            seen2.push(new.clone());
+        });
+        x.set(String::from("a"));
+        x.update(|s| s + "b");
+        assert_eq!(vec!["a", "ab"], seen.take_all());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_observable_write_from_callback() {
+        use alloc::rc::Rc;
+
+        let x = Rc::new(ObservableWithCell::new(0));
+        let x2 = Rc::downgrade(&x);
+        x.observe(move |&t| x2.upgrade().unwrap().set(t + 1));
+        x.set(1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_try_borrow() {
+        let depth = borrow_stack_depth();
+        let x = WithCell::new(String::from("foo"));
+        assert_eq!("foo", *x.try_borrow().unwrap());
+        x.with(|_| {
+            let nested = x.try_borrow().unwrap();
+            assert_eq!(3, nested.len());
+            assert_eq!(depth + 2, borrow_stack_depth());
+        });
+        x.with_mut(|s| {
+            assert!(x.try_borrow().is_none());
+            s.push('d');
+        });
+        let guard = x.borrow_mut();
+        assert!(x.try_borrow().is_none());
+        drop(guard);
+        assert_eq!("food", *x.try_borrow().unwrap());
+        assert_eq!(depth, borrow_stack_depth());
+    }
+
+    #[test]
+    fn test_map_fields_mutably_borrowed_together() {
+        struct Foo {
+            a: Vec<i32>,
+            b: Vec<i32>,
+        }
+        let x = WithCell::new(Foo {
+            a: vec![1, 2],
+            b: Vec::new(),
+        });
+        let a = unsafe { x.map(|foo| &foo.a) };
+        let b = unsafe { x.map(|foo| &foo.b) };
+        a.with_mut(|a| b.with_mut(|b| b.append(a)));
+        let mut a_guard = a.borrow_mut();
+        let mut b_guard = b.borrow_mut();
+        a_guard.push(b_guard.pop().unwrap());
+        drop((a_guard, b_guard));
+        let foo = x.into_inner();
+        assert_eq!(vec![2], foo.a);
+        assert_eq!(vec![1], foo.b);
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_map_same_field_twice() {
+        let x = WithCell::new((vec![1], vec![2]));
+        let first = unsafe { x.map(|t| &t.0) };
+        let first_again = unsafe { x.map(|t| &t.0) };
+        first.with_mut(|_| first_again.with_mut(|_| ()));
+    }
+
+    #[test]
+    fn test_as_cells() {
+        let x = WithCell::new([vec![1], vec![2], vec![3]]);
+        let cells = x.as_cells();
+        cells[0].with_mut(|first| cells[2].with_mut(|last| mem::swap(first, last)));
+        cells[1].with(|_| cells[0].push(4));
+        assert_eq!([vec![3, 4], vec![2], vec![1]], x.into_inner());
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_as_cells_same_element() {
+        let x = WithCell::new([0, 1]);
+        let cells = x.as_cells();
+        cells[1].with(|_| cells[1].set(2));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_as_cells_whole_array() {
+        let x = WithCell::new([0, 1]);
+        x.with(|_| x.as_cells()[0].set(2));
+    }
+
+    #[test]
+    fn test_field_cell_fields_mutably_borrowed_together() {
+        struct Foo {
+            a: Vec<i32>,
+            b: (i32, Vec<i32>),
+        }
+        let x = WithCell::new(Foo {
+            a: vec![1, 2],
+            b: (0, Vec::new()),
+        });
+        let a = field_cell!(x.a);
+        let b = field_cell!(x.b.1);
+        a.with_mut(|a| b.with_mut(|b| b.append(a)));
+        let mut a_guard = a.borrow_mut();
+        let mut b_guard = b.borrow_mut();
+        a_guard.push(b_guard.pop().unwrap());
+        drop((a_guard, b_guard));
+        // A FieldCell can be projected further.
+        let n = field_cell!((&x).b);
+        field_cell!(n.0).set(5);
+        assert!(x.try_borrow().is_some());
+        let foo = x.into_inner();
+        assert_eq!(vec![2], foo.a);
+        assert_eq!((5, vec![1]), foo.b);
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_field_cell_same_field_twice() {
+        let x = WithCell::new((vec![1], vec![2]));
+        let first = field_cell!(x.0);
+        let first_again = field_cell!(x.0);
+        first.with_mut(|_| first_again.with_mut(|_| ()));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_field_cell_while_whole_cell_borrowed() {
+        let x = WithCell::new((1, 2));
+        let first = field_cell!(x.0);
+        x.with(|_| first.set(3));
+    }
+
+    #[test]
+    fn test_nested_shared_reads() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        let sum = x.with(|a| x.with(|b| x.with(|c| a[0] + b[1] + c[2])));
+        assert_eq!(6, sum);
+        let guard = x.borrow();
+        x.with(|v| assert_eq!(v.len(), guard.len()));
+    }
+
+    #[test]
+    fn test_with_shared() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        let sum = x.with_shared(|a| x.with_shared(|b| x.with(|c| a[0] + b[1] + c[2])));
+        assert_eq!(6, sum);
+        // A read that isn't nested at all still works.
+        assert_eq!(vec![1, 2, 3], x.with_shared(|v| v.clone()));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_with_shared_then_mutate() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        x.with_shared(|_| x.with_shared(|_| x.push(4)));
+    }
+
+    #[test]
+    #[should_panic = "address is borrowed"]
+    fn test_nested_shared_reads_then_mutate() {
+        let x = WithCell::new(vec![1, 2, 3]);
+        x.with(|_| {
+            x.with(|_| {});
+            // The inner read is finished, but the outer one is still active.
+            x.push(4);
+        });
+    }
 }
@@ -0,0 +1,16 @@
+// Make sure the public API is usable from a no_std crate. Run this with
+// `--no-default-features --features critical-section` to exercise the global borrow stack.
+#![no_std]
+
+use with_cell::WithCell;
+
+#[test]
+fn test_no_std() {
+    let x = WithCell::new([1, 2, 3]);
+    x.with_mut(|array| array[0] = 10);
+    assert_eq!(x.with(|array| array[0]), 10);
+    assert!(x.borrow().len() == 3);
+    x.with(|_| assert!(x.try_set([0; 3]).is_err()));
+    x.set([4, 5, 6]);
+    assert_eq!(x.into_inner(), [4, 5, 6]);
+}